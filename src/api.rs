@@ -1,26 +1,718 @@
-use crate::{command_history::CommandHistory, commands::Args, Error};
-use serenity::model::prelude::*;
+use crate::{
+    command_history::CommandHistory,
+    commands::{Args, CommandError},
+    i18n::{Locale, Text},
+    reaction_menus, Error,
+};
+use lazy_static::lazy_static;
+use reqwest::{blocking::Response, header};
+use serde_json::{json, Value};
+use serenity::{
+    builder::{CreateEmbed, CreateMessage},
+    model::prelude::*,
+    prelude::*,
+};
+use std::{
+    borrow::Cow,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-/// Send a reply to the channel the message was received on.  
+/// How long all sends are held back after Discord rate-limited us without telling us for how long
+const GLOBAL_RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// What replies are replaced with once they expired, see `expire_reply`
+const EXPIRED_REPLY: &str = "(this result has expired, run the command again)";
+
+/// The longest `Retry-After` that `send_with_retry` waits for before giving up
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// The largest attachment Discord accepts in servers without boosts
+const MAX_ATTACHMENT_SIZE: usize = 8 << 20;
+
+/// How long the buttons of a paginated reply work, see `send_pages`
+const PAGINATION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// Discord's character limits for the parts of an embed
+const EMBED_TITLE_LIMIT: usize = 256;
+const EMBED_DESCRIPTION_LIMIT: usize = 2048;
+const EMBED_FIELD_NAME_LIMIT: usize = 256;
+const EMBED_FIELD_VALUE_LIMIT: usize = 1024;
+const EMBED_FOOTER_LIMIT: usize = 2048;
+const EMBED_AUTHOR_LIMIT: usize = 256;
+
+/// How often and how patiently `send_with_retry` retries a request
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// How long to wait before the first retry. The wait doubles with every further retry
+    pub initial_backoff: Duration,
+    /// Whether timeouts are retried. Turn it off for requests that take long to time out, so that
+    /// a hanging service doesn't block the command for several timeouts in a row
+    pub retry_timeouts: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            retry_timeouts: true,
+        }
+    }
+}
+
+lazy_static! {
+    /// Until when all sends are held back because of a global rate limit
+    static ref GLOBAL_RATE_LIMIT: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+/// Send a reply to the channel the message was received on, which is the thread if it was sent in
+/// one.
 pub fn send_reply(args: &Args, message: &str) -> Result<(), Error> {
+    if args.reference_reply {
+        return send_reply_referenced(args, message, true);
+    }
+    let message = with_help_footer(args, message);
+    let message = &with_notice(args, &message);
+    if args.private_reply {
+        return send_private_reply(args, message);
+    }
+    send_channel_reply(args, message, None)?;
+    Ok(())
+}
+
+/// Send a reply like `send_reply`, but as a Discord reply to the message. `mention` controls
+/// whether the author is pinged by it. Private replies are sent as they are, a DM can't refer to
+/// a message in a guild
+pub fn send_reply_referenced(args: &Args, message: &str, mention: bool) -> Result<(), Error> {
+    let message = with_help_footer(args, message);
+    let message = &with_notice(args, &message);
+    if args.private_reply {
+        return send_private_reply(args, message);
+    }
+    send_channel_reply(args, message, Some(mention))?;
+    Ok(())
+}
+
+/// The reply with the notice of a soft guard appended, see `GuardOutcome::AllowWithNotice`
+fn with_notice<'a>(args: &Args, message: &'a str) -> Cow<'a, str> {
+    match args.notice {
+        Some(notice) => Cow::Owned(format!("{}\n{}", message, notice)),
+        None => Cow::Borrowed(message),
+    }
+}
+
+/// The reply with the usage line and example of the command below it, see `Args::help_footer`
+fn with_help_footer<'a>(args: &Args, message: &'a str) -> Cow<'a, str> {
+    match args.help_footer {
+        Some(footer) => Cow::Owned(format!("{}\n\n{}", message, footer)),
+        None => Cow::Borrowed(message),
+    }
+}
+
+/// Send a reply to the author of the message in a DM, for output that shouldn't be public. Like a
+/// reply in the channel, the DM is edited when the message is. If the DM can't be sent, e.g.
+/// because the author doesn't accept DMs, they're told so in the channel
+pub fn send_private_reply(args: &Args, message: &str) -> Result<(), Error> {
+    let sent = args.msg.author.create_dm_channel(args.cx).and_then(|dm| {
+        let edited = response_exists(args).map(|response_id| {
+            info!("editing DM: {:?}", response_id);
+            dm.id.edit_message(args.cx, response_id, |msg| {
+                msg.0
+                    .insert("allowed_mentions", allowed_mentions(args, false));
+                msg.content(message)
+            })
+        });
+        // The earlier reply may not be a DM, if the edit changed the command
+        if let Some(Ok(_)) = edited {
+            return Ok(());
+        }
+        let response = dm.send_message(args.cx, |msg| {
+            msg.0
+                .insert("allowed_mentions", allowed_mentions(args, false));
+            msg.content(message)
+        })?;
+        remember_response(args, response.id);
+        Ok(())
+    });
+    match sent {
+        Ok(_) => Ok(()),
+        Err(SerenityError::Http(e)) if is_forbidden(&e) => {
+            info!(
+                "Can't DM {}, replying in the channel",
+                args.msg.author.tag()
+            );
+            send_channel_reply(args, &Locale::of(args).text(Text::CantSendDm), None)?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Send a reply to the channel. If `reference` is set, the reply is a Discord reply to the message,
+/// pinging the author if it's `Some(true)`. Returns the ID of the reply
+fn send_channel_reply(
+    args: &Args,
+    message: &str,
+    reference: Option<bool>,
+) -> Result<MessageId, Error> {
     if let Some(response_id) = response_exists(args) {
         info!("editing message: {:?}", response_id);
+        rate_limited(|| {
+            args.msg
+                .channel_id
+                .edit_message(&args.cx, response_id, |msg| {
+                    msg.0
+                        .insert("allowed_mentions", allowed_mentions(args, false));
+                    msg.content(message)
+                })
+        })?;
+        Ok(response_id)
+    } else {
+        let response = rate_limited(|| {
+            args.msg.channel_id.send_message(&args.cx, |msg| {
+                if reference.is_some() {
+                    refer_to(msg, args.msg);
+                }
+                let replied_user = reference.unwrap_or(false);
+                msg.0
+                    .insert("allowed_mentions", allowed_mentions(args, replied_user));
+                msg.content(message)
+            })
+        })?;
+        remember_response(args, response.id);
+        Ok(response.id)
+    }
+}
+
+/// Send a reply with `bytes` attached as a file named `filename`, e.g. for output too long for a
+/// message. `content` may be empty. Attachments larger than Discord allows are refused with an
+/// error for the user. Since attachments can't be added by editing a message, an earlier reply to
+/// the message is replaced
+pub fn send_reply_with_attachment(
+    args: &Args,
+    content: &str,
+    filename: &str,
+    bytes: &[u8],
+) -> Result<(), Error> {
+    if bytes.len() > MAX_ATTACHMENT_SIZE {
+        let reply = Locale::of(args).text(Text::AttachmentTooLarge(MAX_ATTACHMENT_SIZE >> 20));
+        return Err(CommandError::User(reply).into());
+    }
+    let content = with_help_footer(args, content);
+    let content = with_notice(args, &content);
+
+    if args.private_reply {
+        let dm = args.msg.author.create_dm_channel(args.cx)?;
+        if let Some(response_id) = response_exists(args) {
+            // Ignored, the earlier reply may not be a DM
+            let _ = dm.id.delete_message(args.cx, response_id);
+        }
+        let response = rate_limited(|| {
+            dm.send_files(args.cx, vec![(bytes, filename)], |msg| {
+                msg.0
+                    .insert("allowed_mentions", allowed_mentions(args, false));
+                msg.content(&content)
+            })
+        })?;
+        remember_response(args, response.id);
+        return Ok(());
+    }
+
+    if let Some(response_id) = response_exists(args) {
+        rate_limited(|| args.msg.channel_id.delete_message(args.cx, response_id))?;
+    }
+    let response = rate_limited(|| {
         args.msg
             .channel_id
-            .edit_message(&args.cx, response_id, |msg| msg.content(message))?;
+            .send_files(args.cx, vec![(bytes, filename)], |msg| {
+                if args.reference_reply {
+                    refer_to(msg, args.msg);
+                }
+                msg.0.insert(
+                    "allowed_mentions",
+                    allowed_mentions(args, args.reference_reply),
+                );
+                msg.content(&content)
+            })
+    })?;
+    remember_response(args, response.id);
+    Ok(())
+}
+
+/// Make the message a Discord reply to `to`. Serenity has no builder method for this yet
+fn refer_to<'a, 'b>(msg: &'a mut CreateMessage<'b>, to: &Message) -> &'a mut CreateMessage<'b> {
+    let mut reference = json!({
+        "message_id": to.id.to_string(),
+        "channel_id": to.channel_id.to_string(),
+    });
+    if let Some(guild_id) = to.guild_id {
+        reference["guild_id"] = guild_id.to_string().into();
+    }
+    msg.0.insert("message_reference", reference);
+    msg
+}
+
+/// What replies to the message may ping. Users always, roles, `@everyone` and `@here` only if
+/// the command allows it, see `Command::allow_mass_mentions`. `replied_user` is whether the author
+/// of a message replied to is pinged, see `refer_to`. Serenity has no builder method for this yet
+fn allowed_mentions(args: &Args, replied_user: bool) -> Value {
+    let parse = match args.allow_mass_mentions {
+        true => json!(["users", "roles", "everyone"]),
+        false => json!(["users"]),
+    };
+    json!({ "parse": parse, "replied_user": replied_user })
+}
+
+/// Send an embed as the reply to the message, like `send_reply`. Parts of the embed that are too
+/// long are shortened, see `fit_embed_to_limits`. The notice of a soft guard goes above the embed
+pub fn send_embed(
+    args: &Args,
+    build: impl Fn(&mut CreateEmbed) -> &mut CreateEmbed,
+) -> Result<(), Error> {
+    if let Some(response_id) = response_exists(args) {
+        info!("editing message: {:?}", response_id);
+        rate_limited(|| {
+            args.msg
+                .channel_id
+                .edit_message(&args.cx, response_id, |msg| {
+                    msg.0
+                        .insert("allowed_mentions", allowed_mentions(args, false));
+                    msg.content(args.notice.unwrap_or_default())
+                        .embed(|e| fit_embed_to_limits(build(e)))
+                })
+        })?;
     } else {
-        let response = args.msg.channel_id.say(&args.cx, message)?;
+        let response = rate_limited(|| {
+            args.msg.channel_id.send_message(&args.cx, |msg| {
+                msg.0
+                    .insert("allowed_mentions", allowed_mentions(args, false));
+                msg.content(args.notice.unwrap_or_default())
+                    .embed(|e| fit_embed_to_limits(build(e)))
+            })
+        })?;
+        remember_response(args, response.id);
+    }
 
-        let mut data = args.cx.data.write();
-        let history = data.get_mut::<CommandHistory>().unwrap();
-        history.insert(args.msg.id, response.id);
+    Ok(())
+}
+
+/// Send a reply that's split into pages of an embed, with ◀️ and ▶️ reactions to flip through
+/// them until `PAGINATION_TIMEOUT` passed. If `invoker_only` is set, only the author of the
+/// message can flip pages. A single page is sent as an embed without reactions. The notice of a
+/// soft guard stays above the pages.
+pub fn send_pages(args: &Args, pages: Vec<String>, invoker_only: bool) -> Result<(), Error> {
+    let pages = match pages.len() {
+        0 => return send_reply(args, "(nothing to show)"),
+        _ => Arc::new(pages),
+    };
+    let channel_id = args.msg.channel_id;
+
+    let response_id = match response_exists(args) {
+        Some(response_id) => {
+            info!("editing message: {:?}", response_id);
+            rate_limited(|| {
+                channel_id.edit_message(args.cx, response_id, |msg| {
+                    msg.0
+                        .insert("allowed_mentions", allowed_mentions(args, false));
+                    msg.content(args.notice.unwrap_or_default())
+                        .embed(|e| fit_embed_to_limits(page_embed(e, &pages, 0)))
+                })
+            })?;
+            // The old menu may still be around, and it would flip pages twice
+            reaction_menus::remove(args.cx, response_id);
+            response_id
+        }
+        None => {
+            let response = rate_limited(|| {
+                channel_id.send_message(args.cx, |msg| {
+                    msg.0
+                        .insert("allowed_mentions", allowed_mentions(args, false));
+                    msg.content(args.notice.unwrap_or_default())
+                        .embed(|e| fit_embed_to_limits(page_embed(e, &pages, 0)))
+                })
+            })?;
+            remember_response(args, response.id);
+            response.id
+        }
+    };
+    if pages.len() == 1 {
+        return Ok(());
     }
+    rate_limited(|| channel_id.create_reaction(args.cx, response_id, '◀'))?;
+    rate_limited(|| channel_id.create_reaction(args.cx, response_id, '▶'))?;
 
+    let current = Arc::new(Mutex::new(0));
+    let author_id = args.msg.author.id;
+    let mut registrations = Vec::new();
+    for &(emoji, step) in &[('◀', -1), ('▶', 1)] {
+        let pages = Arc::clone(&pages);
+        let current = Arc::clone(&current);
+        let registration =
+            reaction_menus::register(args.cx, response_id, emoji, move |cx, reaction| {
+                if invoker_only && reaction.user_id != author_id {
+                    return Ok(());
+                }
+                // Take the reaction back off, so that the same button can be pressed again. Needs
+                // the Manage Messages permission, without it the user has to remove it themselves
+                let _ = reaction.delete(cx);
+
+                let mut current = current.lock();
+                let next = *current as isize + step;
+                if next < 0 || next as usize >= pages.len() {
+                    return Ok(());
+                }
+                *current = next as usize;
+                rate_limited(|| {
+                    channel_id.edit_message(cx, response_id, |msg| {
+                        msg.embed(|e| fit_embed_to_limits(page_embed(e, &pages, *current)))
+                    })
+                })?;
+                Ok(())
+            });
+        registrations.push(registration);
+    }
+
+    // Only this menu's handlers, an edit of the command may have set up a new menu in the meantime
+    let cx = args.cx.clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(PAGINATION_TIMEOUT);
+        for registration in registrations {
+            reaction_menus::unregister(&cx, response_id, registration);
+        }
+    });
     Ok(())
 }
 
+/// Post `text` to the channel of the message with `reactions` to pick from, e.g. for a
+/// confirmation. It's not the reply to the message, so the reply can still be sent after it. If
+/// the reactions can't be added, the message is deleted again. Returns the ID of the message
+pub fn send_prompt(args: &Args, text: &str, reactions: &[char]) -> Result<MessageId, Error> {
+    let channel_id = args.msg.channel_id;
+    let prompt = rate_limited(|| {
+        channel_id.send_message(args.cx, |msg| {
+            msg.0
+                .insert("allowed_mentions", allowed_mentions(args, false));
+            msg.content(text)
+        })
+    })?;
+    for &reaction in reactions {
+        if let Err(e) = rate_limited(|| channel_id.create_reaction(args.cx, prompt.id, reaction)) {
+            let _ = prompt.delete(args.cx);
+            return Err(e);
+        }
+    }
+    Ok(prompt.id)
+}
+
+fn page_embed<'a>(
+    embed: &'a mut CreateEmbed,
+    pages: &[String],
+    page: usize,
+) -> &'a mut CreateEmbed {
+    embed.description(&pages[page]);
+    if pages.len() > 1 {
+        embed.footer(|f| f.text(format!("Page {}/{}", page + 1, pages.len())));
+    }
+    embed
+}
+
+/// Status message of a command that takes a while, see `Args::progress`
+pub struct Progress {
+    cx: Context,
+    channel_id: ChannelId,
+    /// `None` if no status message was posted, see `start_progress`
+    message_id: Option<MessageId>,
+    allowed_mentions: Value,
+}
+
+impl Progress {
+    /// Replace the status text. Failing to is only logged, the command can go on without it
+    pub fn update(&self, text: &str) {
+        let message_id = match self.message_id {
+            Some(message_id) => message_id,
+            None => return,
+        };
+        let result = rate_limited(|| {
+            self.channel_id.edit_message(&self.cx, message_id, |msg| {
+                msg.0
+                    .insert("allowed_mentions", self.allowed_mentions.clone());
+                msg.content(text)
+            })
+        });
+        if let Err(e) = result {
+            warn!("Can't update progress message {:?}: {}", message_id, e);
+        }
+    }
+}
+
+/// Post the initial status text as the reply to the message. The reply is edited in place after
+/// that, by `Progress::update` as well as by `send_reply` once the result is ready. For private
+/// replies nothing is posted, since the result goes to a DM and would leave the status behind
+pub fn start_progress(args: &Args, text: &str) -> Result<Progress, Error> {
+    let message_id = match args.private_reply {
+        true => None,
+        false => Some(send_channel_reply(args, text, None)?),
+    };
+    Ok(Progress {
+        cx: args.cx.clone(),
+        channel_id: args.msg.channel_id,
+        message_id,
+        allowed_mentions: allowed_mentions(args, false),
+    })
+}
+
+/// Replace the reply to the message with a notice once `ttl` passed, without blocking the caller
+pub fn expire_reply(args: &Args, ttl: Duration) {
+    let response_id = match response_exists(args) {
+        Some(response_id) => response_id,
+        None => return,
+    };
+    let cx = args.cx.clone();
+    let channel_id = args.msg.channel_id;
+
+    expire_later(ttl, response_id, move |text| {
+        rate_limited(|| channel_id.edit_message(&cx, response_id, |msg| msg.content(text)))?;
+        Ok(())
+    });
+}
+
+/// Replace the reply with `EXPIRED_REPLY` through `edit` once `ttl` passed, on a thread of its own
+fn expire_later(
+    ttl: Duration,
+    response_id: MessageId,
+    edit: impl FnOnce(&str) -> Result<(), Error> + Send + 'static,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        std::thread::sleep(ttl);
+        // Fails if the reply was deleted in the meantime, which is fine
+        if let Err(e) = edit(EXPIRED_REPLY) {
+            info!("Can't expire reply {:?}: {}", response_id, e);
+        }
+    })
+}
+
+/// Send a request to an external service, retrying it with exponential backoff as long as it
+/// fails in a way that may go away by itself: timeouts, unless the policy says otherwise, 5xx
+/// responses and 429 responses, whose `Retry-After` is respected. `request` is called once per
+/// attempt.
+///
+/// Other responses are returned as they are, including error statuses. Once the retries are used
+/// up, the error tells the user to try again later.
+pub fn send_with_retry(
+    args: &Args,
+    policy: RetryPolicy,
+    request: impl Fn() -> reqwest::Result<Response>,
+) -> Result<Response, Error> {
+    let mut backoff = policy.initial_backoff;
+    let mut retries = 0;
+    loop {
+        let result = request();
+        let (wait, failure) = match &result {
+            Ok(response) if response.status().as_u16() == 429 => (
+                retry_after(response).unwrap_or(backoff),
+                response.status().to_string(),
+            ),
+            Ok(response) if response.status().is_server_error() => {
+                (backoff, response.status().to_string())
+            }
+            Err(e) if e.is_timeout() => (backoff, e.to_string()),
+            _ => return Ok(result?),
+        };
+
+        let timed_out = matches!(&result, Err(e) if e.is_timeout());
+        if retries == policy.max_retries
+            || wait > MAX_RETRY_AFTER
+            || (timed_out && !policy.retry_timeouts)
+        {
+            warn!("Giving up after {} retries: {}", retries, failure);
+            let reply = Locale::of(args).text(Text::ServiceUnavailable);
+            return Err(CommandError::User(reply).into());
+        }
+        info!("Request failed ({}), retrying in {:?}", failure, wait);
+        std::thread::sleep(wait);
+        backoff *= 2;
+        retries += 1;
+    }
+}
+
+/// The wait a 429 response asks for. Only the delay in seconds is supported, not the HTTP date
+fn retry_after(response: &Response) -> Option<Duration> {
+    let seconds = response.headers().get(header::RETRY_AFTER)?.to_str().ok()?;
+    seconds.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// Perform a send operation behind the gate shared by all sends.
+///
+/// Serenity retries rate-limited requests itself, as long as Discord says how long to wait. A 429
+/// it couldn't retry ends up here instead and closes the gate for `GLOBAL_RATE_LIMIT_BACKOFF`.
+/// While the gate is closed, every send waits for it while holding the lock, so concurrent sends
+/// queue up behind each other instead of all running into the rate limit at once.
+fn rate_limited<T>(send: impl FnOnce() -> serenity::Result<T>) -> Result<T, Error> {
+    {
+        let mut blocked_until = GLOBAL_RATE_LIMIT.lock();
+        if let Some(until) = *blocked_until {
+            let now = Instant::now();
+            if until > now {
+                warn!("Globally rate limited, waiting {:?}", until - now);
+                std::thread::sleep(until - now);
+            }
+            *blocked_until = None;
+        }
+    }
+
+    match send() {
+        Err(SerenityError::Http(e)) if is_rate_limit(&e) => {
+            *GLOBAL_RATE_LIMIT.lock() = Some(Instant::now() + GLOBAL_RATE_LIMIT_BACKOFF);
+            Err(SerenityError::Http(e).into())
+        }
+        result => Ok(result?),
+    }
+}
+
+fn is_rate_limit(e: &HttpError) -> bool {
+    match e {
+        HttpError::UnsuccessfulRequest(response) => response.status_code.as_u16() == 429,
+        _ => false,
+    }
+}
+
+fn is_forbidden(e: &HttpError) -> bool {
+    match e {
+        HttpError::UnsuccessfulRequest(response) => response.status_code.as_u16() == 403,
+        _ => false,
+    }
+}
+
+/// Shorten every part of the embed that's longer than Discord allows, so that sending it doesn't
+/// fail. Cut off text is marked with an ellipsis.
+pub fn fit_embed_to_limits(embed: &mut CreateEmbed) -> &mut CreateEmbed {
+    truncate_embed_text(embed.0.get_mut("title"), EMBED_TITLE_LIMIT);
+    truncate_embed_text(embed.0.get_mut("description"), EMBED_DESCRIPTION_LIMIT);
+    if let Some(Value::Object(author)) = embed.0.get_mut("author") {
+        truncate_embed_text(author.get_mut("name"), EMBED_AUTHOR_LIMIT);
+    }
+    if let Some(Value::Object(footer)) = embed.0.get_mut("footer") {
+        truncate_embed_text(footer.get_mut("text"), EMBED_FOOTER_LIMIT);
+    }
+    if let Some(Value::Array(fields)) = embed.0.get_mut("fields") {
+        for field in fields {
+            if let Value::Object(field) = field {
+                truncate_embed_text(field.get_mut("name"), EMBED_FIELD_NAME_LIMIT);
+                truncate_embed_text(field.get_mut("value"), EMBED_FIELD_VALUE_LIMIT);
+            }
+        }
+    }
+    embed
+}
+
+fn truncate_embed_text(text: Option<&mut Value>, limit: usize) {
+    if let Some(Value::String(text)) = text {
+        if text.chars().count() > limit {
+            *text = text.chars().take(limit - 1).chain(Some('…')).collect();
+        }
+    }
+}
+
+fn remember_response(args: &Args, response_id: MessageId) {
+    let mut data = args.cx.data.write();
+    let history = data.get_mut::<CommandHistory>().unwrap();
+    history.insert(args.msg.id, response_id);
+}
+
 fn response_exists(args: &Args) -> Option<MessageId> {
     let data = args.cx.data.read();
     let history = data.get::<CommandHistory>().unwrap();
     history.get(&args.msg.id).copied()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serenity::http::error::ErrorResponse;
+
+    fn status(code: u16) -> SerenityError {
+        SerenityError::Http(Box::new(HttpError::UnsuccessfulRequest(ErrorResponse {
+            status_code: reqwest::StatusCode::from_u16(code).unwrap(),
+            url: "https://discord.com/api/v8/channels".parse().unwrap(),
+            error: serde_json::from_value(json!({ "code": 0, "message": "" })).unwrap(),
+        })))
+    }
+
+    #[test]
+    fn global_rate_limit_holds_back_sends() {
+        // Other errors leave the gate open
+        assert!(rate_limited(|| Err::<(), _>(status(500))).is_err());
+        assert!(GLOBAL_RATE_LIMIT.lock().is_none());
+
+        assert!(rate_limited(|| Err::<(), _>(status(429))).is_err());
+        let blocked_until = GLOBAL_RATE_LIMIT.lock().unwrap();
+        assert!(blocked_until > Instant::now());
+
+        // Don't wait the whole backoff
+        let wait = Duration::from_millis(100);
+        *GLOBAL_RATE_LIMIT.lock() = Some(Instant::now() + wait);
+        let started_at = Instant::now();
+        assert_eq!(rate_limited(|| Ok(1)).unwrap(), 1);
+        assert!(started_at.elapsed() >= wait);
+        assert!(GLOBAL_RATE_LIMIT.lock().is_none());
+    }
+
+    #[test]
+    fn long_embed_fields_are_truncated() {
+        let long = "a".repeat(EMBED_FIELD_VALUE_LIMIT + 1);
+        let fitting = "b".repeat(EMBED_FIELD_VALUE_LIMIT);
+        let mut embed = CreateEmbed::default();
+        embed
+            .field("long", &long, false)
+            .field("fitting", &fitting, false);
+        fit_embed_to_limits(&mut embed);
+
+        let fields = embed.0["fields"].as_array().unwrap();
+        let value = fields[0]["value"].as_str().unwrap();
+        assert_eq!(value.chars().count(), EMBED_FIELD_VALUE_LIMIT);
+        assert!(value.ends_with('…'));
+        assert_eq!(fields[1]["value"].as_str(), Some(fitting.as_str()));
+    }
+
+    #[test]
+    fn replies_expire_after_their_ttl() {
+        let ttl = Duration::from_millis(50);
+        let (sender, expired) = std::sync::mpsc::channel();
+        let started_at = Instant::now();
+        let expiry = expire_later(ttl, MessageId(1), move |text| {
+            sender
+                .send((text.to_owned(), started_at.elapsed()))
+                .unwrap();
+            Ok(())
+        });
+        let (text, after) = expired.recv().unwrap();
+        assert_eq!(text, EXPIRED_REPLY);
+        assert!(after >= ttl);
+        expiry.join().unwrap();
+
+        // A reply that's gone already is only logged
+        let expiry = expire_later(Duration::from_millis(0), MessageId(1), |_| {
+            Err("Unknown Message".into())
+        });
+        expiry.join().unwrap();
+    }
+
+    #[test]
+    fn embed_text_is_truncated_between_characters() {
+        let fitting = "名".repeat(EMBED_FIELD_VALUE_LIMIT);
+        let long = "🦀".repeat(EMBED_FIELD_VALUE_LIMIT + 1);
+        let mut embed = CreateEmbed::default();
+        embed
+            .field("名前", &fitting, false)
+            .field("🦀", &long, false);
+        fit_embed_to_limits(&mut embed);
+
+        let fields = embed.0["fields"].as_array().unwrap();
+        assert_eq!(fields[0]["value"].as_str(), Some(fitting.as_str()));
+        let value = fields[1]["value"].as_str().unwrap();
+        let expected = format!("{}…", "🦀".repeat(EMBED_FIELD_VALUE_LIMIT - 1));
+        assert_eq!(value, expected);
+    }
+}
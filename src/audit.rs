@@ -0,0 +1,77 @@
+use crate::{commands::CommandError, Error};
+use serenity::{model::prelude::*, prelude::*};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How a command run ended
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuditOutcome {
+    Success,
+    /// The command's guard rejected the author
+    Denied,
+    Error,
+}
+
+impl AuditOutcome {
+    pub fn of(result: &Result<(), Error>) -> Self {
+        match result {
+            Ok(()) => AuditOutcome::Success,
+            Err(e) if CommandError::is_permission(e) => AuditOutcome::Denied,
+            Err(_) => AuditOutcome::Error,
+        }
+    }
+}
+
+/// Record of a single command run
+#[derive(Clone, Debug)]
+pub struct CommandAudit {
+    pub user_id: UserId,
+    pub user_name: String,
+    /// Full name of the command, including the groups it's in
+    pub command: String,
+    /// The message that invoked the command, as it was sent
+    pub content: String,
+    pub timestamp: SystemTime,
+    pub outcome: AuditOutcome,
+}
+
+/// Destination for the records of every command run, see `Commands::audit`
+pub trait AuditSink: Send + Sync {
+    fn record(&self, entry: CommandAudit);
+}
+
+/// Appends one tab-separated line per command run to a file
+pub struct FileAuditSink {
+    file: Mutex<File>,
+}
+
+impl FileAuditSink {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl AuditSink for FileAuditSink {
+    fn record(&self, entry: CommandAudit) {
+        let timestamp = entry
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+        // Keep each record on a single line
+        let content = entry.content.replace('\n', "\\n");
+        let line = format!(
+            "{}\t{}\t{}\t{}\t{:?}\t{}\n",
+            timestamp, entry.user_id, entry.user_name, entry.command, entry.outcome, content
+        );
+        if let Err(e) = self.file.lock().write_all(line.as_bytes()) {
+            error!("Can't write audit record: {}", e);
+        }
+    }
+}
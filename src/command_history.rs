@@ -8,7 +8,12 @@ impl TypeMapKey for CommandHistory {
     type Value = IndexMap<MessageId, MessageId>;
 }
 
-pub fn replay_message(cx: Context, ev: MessageUpdateEvent, cmds: &Commands) -> Result<(), Error> {
+pub fn replay_message(
+    cx: Context,
+    old: Option<Message>,
+    ev: MessageUpdateEvent,
+    cmds: &Commands,
+) -> Result<(), Error> {
     if let (Some(created), Some(edited)) = (ev.timestamp, ev.edited_timestamp) {
         // Only track edits for recent messages
         if (edited - created).num_minutes() < 60 {
@@ -16,7 +21,18 @@ pub fn replay_message(cx: Context, ev: MessageUpdateEvent, cmds: &Commands) -> R
             msg.id(ev.id)
                 .channel_id(ev.channel_id)
                 .content(ev.content.unwrap_or_else(String::new));
-            cmds.execute(&cx, &msg.build());
+            // Commands need to know who ran them, e.g. to ignore banned users
+            if let Some(author) = ev
+                .author
+                .or_else(|| old.as_ref().map(|old| old.author.clone()))
+            {
+                msg.author(author);
+            }
+            if let Some(guild_id) = ev.guild_id {
+                msg.guild_id(guild_id);
+            }
+            let old_content = old.as_ref().map(|old| old.content.as_str());
+            cmds.execute_edit(&cx, old_content, &msg.build());
         }
     }
 
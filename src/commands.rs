@@ -1,7 +1,23 @@
-use crate::Error;
+use crate::{
+    audit::{AuditOutcome, AuditSink, CommandAudit},
+    i18n::{Locale, Text},
+    metrics::{CommandStats, Metrics},
+    reaction_menus, Error,
+};
+use indexmap::IndexMap;
+use regex::Regex;
 use reqwest::blocking::Client as HttpClient;
-use serenity::{model::prelude::*, prelude::*};
-use std::collections::HashMap;
+use serde_json::{json, Value};
+use serenity::{cache::Cache, model::prelude::*, prelude::*, utils::CustomMessage};
+use std::any::Any;
+use std::borrow::{Borrow, Cow};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 pub const PREFIXES: &[&str] = &[
     "?",
@@ -19,166 +35,4144 @@ pub const PREFIXES: &[&str] = &[
     "hey fewwis, can you ",
 ];
 
+pub type Action = Arc<dyn Fn(&Args<'_>) -> Result<(), Error> + Send + Sync>;
+
+/// Replies with the command-specific help of a command
+pub type HelpFn = Box<dyn Fn(&Args<'_>) -> Result<(), Error> + Send + Sync>;
+
 pub enum CommandHandler {
     Help,
     Custom {
-        action: Box<dyn Fn(&Args<'_>) -> Result<(), Error> + Send + Sync>,
+        action: Action,
         /// Multiline description of the command to display for the command-specific help command
-        help: Box<dyn Fn(&Args<'_>) -> Result<(), Error> + Send + Sync>,
+        help: HelpFn,
     },
+    Group(CommandGroup),
+}
+
+/// A command made up of subcommands, like `?config show` and `?config set`
+pub struct CommandGroup {
+    subcommands: Vec<Command>,
+    /// Subcommand to run when the group is invoked without naming one of its subcommands, e.g.
+    /// `?config` running `?config show`
+    pub default: Option<&'static str>,
+}
+
+impl CommandGroup {
+    pub fn add(
+        &mut self,
+        command: &'static str,
+        handler: impl Fn(&Args) -> Result<(), Error> + Send + Sync + 'static,
+        inline_help: &'static str,
+        long_help: impl Fn(&Args) -> Result<(), Error> + Send + Sync + 'static,
+    ) -> &mut Command {
+        self.subcommands.push(Command::new(
+            command,
+            inline_help,
+            CommandHandler::Custom {
+                action: Arc::new(handler),
+                help: Box::new(long_help),
+            },
+        ));
+        self.subcommands.last_mut().unwrap()
+    }
+
+    fn usage(&self, prefix: &str, group_name: &str) -> String {
+        let names = self
+            .subcommands
+            .iter()
+            .map(|cmd| &*cmd.name)
+            .collect::<Vec<_>>();
+        format!("Usage: `{}{} <{}>`", prefix, group_name, names.join("|"))
+    }
+
+    /// The usage line followed by every subcommand with its inline help
+    fn help(&self, prefix: &str, group_name: &str) -> String {
+        let mut help = format!("{}\n```\n", self.usage(prefix, group_name));
+        for subcommand in &self.subcommands {
+            help += &format!("\t{:<12}{}\n", subcommand.name, subcommand.inline_help);
+        }
+        help += &format!(
+            "\nType {}help {} subcommand for more info on a subcommand.",
+            prefix, group_name
+        );
+        help += "\n```";
+        help
+    }
+}
+
+/// What a command expects after its key-value parameters, for its usage line
+#[derive(Clone, Copy, PartialEq)]
+pub enum Body {
+    None,
+    Optional(&'static str),
+    Required(&'static str),
+}
+
+/// State shared by all commands, like a database connection pool, see `Commands::set_data`
+pub type SharedData = dyn Any + Send + Sync;
+
+/// Decides whether the author of the message may use a command
+pub type GuardFn = Box<dyn Fn(&Args) -> Result<bool, Error> + Send + Sync>;
+
+/// What a soft guard decided, see `Command::soft_guard`
+pub enum GuardOutcome {
+    Allow,
+    /// Run the command, and append the notice to its replies
+    AllowWithNotice(String),
+    /// Don't run the command, and reply this instead
+    Deny(String),
+}
+
+/// Decides whether the author of the message may use a command, and what to tell them about it
+pub type SoftGuardFn = Box<dyn Fn(&Args) -> Result<GuardOutcome, Error> + Send + Sync>;
+
+/// What the value of a parameter has to be, see `Command::param_types`. Each type has an accessor
+/// on `Args`, like `Args::param_user` for `ParamType::User`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParamType {
+    User,
+    Channel,
+    #[allow(dead_code)] // No command of the bot takes a role yet
+    Role,
+    Int,
+    Duration,
 }
 
+impl ParamType {
+    fn check(self, key: &str, value: &str) -> Result<(), Error> {
+        match self {
+            ParamType::User => parse_user(key, value).map(drop),
+            ParamType::Channel => parse_channel(key, value).map(drop),
+            ParamType::Role => parse_role(key, value).map(drop),
+            ParamType::Int => parse_int(key, value).map(drop),
+            ParamType::Duration => parse_duration_param(key, value).map(drop),
+        }
+    }
+}
+
+/// How long a user has to wait between two uses of a command, see `Command::cooldown`
+pub struct Cooldown {
+    pub duration: Duration,
+    /// How long nobody can use the command in a channel after it was used there, so that an
+    /// expensive command can't be spammed by several users together
+    pub channel_duration: Option<Duration>,
+    /// Users this guard lets through, e.g. moderators, aren't subject to the cooldown. A failing
+    /// bypass guard is logged and doesn't let the user through
+    pub bypass: Option<GuardFn>,
+}
+
+impl Cooldown {
+    #[allow(dead_code)] // None of the bot's commands has a cooldown yet
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            channel_duration: None,
+            bypass: None,
+        }
+    }
+}
+
+/// Error of a command that tells the errors the user should see apart from internal ones. Commands
+/// return it boxed like any other error, errors of other types count as `Internal`
+#[derive(Debug)]
+pub enum CommandError {
+    /// Caused by the input or worth telling the user about otherwise. The message is replied
+    User(String),
+    /// The command's guard rejected the author of the message. Holds the localized reply
+    Permission(String),
+    /// What the command was asked for doesn't exist. The message is replied
+    NotFound(String),
+    /// Something broke. It's only logged, the user is told that an internal error occurred
+    Internal(Error),
+}
+
+impl CommandError {
+    /// Whether the error was caused by the command's guard rejecting the author
+    pub fn is_permission(error: &Error) -> bool {
+        matches!(error.downcast_ref(), Some(CommandError::Permission(_)))
+    }
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::User(message)
+            | CommandError::Permission(message)
+            | CommandError::NotFound(message) => write!(f, "{}", message),
+            CommandError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CommandError::Internal(e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
+
+/// Runs before every command, see `Commands::before`
+pub type BeforeHook = Box<dyn Fn(&Args) -> Result<bool, Error> + Send + Sync>;
+
+/// Runs after every command with its outcome, see `Commands::after`
+pub type AfterHook = Box<dyn Fn(&Args, &Result<(), Error>) + Send + Sync>;
+
 pub struct Command {
-    pub name: &'static str,
+    pub name: Cow<'static, str>,
     pub aliases: &'static [&'static str],
+    /// Heading the command is listed under in the help menu
+    pub category: Option<&'static str>,
+    /// Checked before running the command. Commands the guard rejects are also hidden from the
+    /// help menu
+    pub guard: Option<GuardFn>,
+    /// Checked after `guard`, for gates that come with a message, like a nudge to level up for
+    /// users who can use the command but not all of it. Doesn't hide the command from the help
+    /// menu
+    pub soft_guard: Option<SoftGuardFn>,
+    /// Who the guard lets through, like `moderators`, for the command manifest
+    pub guard_label: Option<&'static str>,
+    /// For guards that are expensive to evaluate, e.g. because they call the Discord API: reuse
+    /// the guard's result for the same user for this long
+    pub guard_cache: Option<Duration>,
     pub broadcast_typing: bool,
     /// Should be a short sentence to display inline in the help menu
     pub inline_help: &'static str,
+    /// Example invocation shown along with the command-specific help, like `?crate serde`
+    pub example: Option<&'static str>,
+    /// Name of an optional positional argument in front of the key-value parameters, like `time`
+    /// in `?remind 10m text=…`. It's passed on in `params` under this name. It has to come first,
+    /// after a key-value parameter it would be taken as the body
+    pub positional: Option<&'static str>,
+    /// Key-value parameters the command understands, for its usage line. If any are declared,
+    /// other keys are rejected
+    pub params: &'static [&'static str],
+    /// Match keys of key-value parameters against the declared `params` ignoring case, so that
+    /// `Limit=10` is passed on as `limit`. Values are passed on as they are
+    pub case_insensitive_params: bool,
+    /// Also accept whitespace around the `=` of key-value parameters, like `limit = 10`. Not for
+    /// commands whose body may start with something like `x = 5`, that would be taken as a
+    /// parameter
+    pub lenient_params: bool,
+    /// Let the values of key-value parameters and the positional argument contain whitespace if
+    /// they're in double quotes, like `name="my tag"`. The quotes aren't part of the value
+    pub quoted_values: bool,
+    /// Values of key-value parameters for when they aren't given, like `("channel", "stable")`.
+    /// They end up in `params`, but not in `params_in_order`, which only has what was given
+    pub defaults: &'static [(&'static str, &'static str)],
+    /// What the command expects as its body, for its usage line. If a required body is missing,
+    /// the usage line is replied instead of running the command
+    pub body: Body,
     pub handler: CommandHandler,
+    /// Extracts directives embedded in the command body into `params`, see `comment_directives`.
+    /// The language tag of a code block body is passed on too, under `<body name>_lang`, or
+    /// `code_lang` for commands without a named body
+    pub directives: Option<DirectiveParser>,
+    /// Refuse to run the command if a key-value parameter is given twice, instead of silently
+    /// using the last value
+    pub reject_duplicate_params: bool,
+    /// Patterns that the values of the given key-value parameters have to match as a whole
+    pub validators: Vec<(&'static str, Regex)>,
+    /// Types the values of the given parameters have to have, checked before running the command,
+    /// so that the typed accessors like `Args::param_user` don't fail on them
+    pub param_types: &'static [(&'static str, ParamType)],
+    /// Replace the reply with a notice once it's this old, for commands whose output goes stale
+    pub reply_ttl: Option<Duration>,
+    /// When a message is edited, only run the command if the edit introduced it. Edits to a
+    /// message that already named this command, and the same subcommand of it, don't run it a
+    /// second time
+    pub skip_rerun_on_edit: bool,
+    /// Give up on the command and reply that it timed out if it takes longer than this
+    pub timeout: Option<Duration>,
+    /// Prompt the author of the command has to confirm by reacting before the command runs, for
+    /// destructive commands
+    pub confirmation: Option<&'static str>,
+    /// Reply to the author in a DM instead of in the channel, for output that shouldn't be public
+    pub private_reply: bool,
+    /// Send the reply as a Discord reply to the message that ran the command, so that it's linked
+    /// to it in busy channels
+    pub reference_reply: bool,
+    /// Let replies ping roles, `@everyone` and `@here`. Off by default, so that a command echoing
+    /// user input can't be used to mass-ping the server. Users can always be pinged
+    pub allow_mass_mentions: bool,
+    /// Refuse to run the command for a user who ran it less than this long ago
+    pub cooldown: Option<Cooldown>,
+    /// The most characters a single key-value parameter or the body may have, so that handlers
+    /// and the APIs they call aren't fed arbitrarily large input
+    pub max_argument_length: usize,
+    /// Cut arguments longer than `max_argument_length` down to it, instead of refusing to run
+    /// the command
+    pub truncate_long_arguments: bool,
+    /// Don't log each run of the command and the mistakes of its users at the info level, for
+    /// commands used so often that it floods the logs. Slow runs and errors are still logged
+    pub quiet: bool,
+    /// Run the handler on a thread of its own, so that a slow command doesn't hold up the
+    /// messages after it. To the bot the command is done once the thread started: metrics,
+    /// `after` hooks and audit sinks see it succeed right away, and the replies of later commands
+    /// may come before its own. Its errors are still logged and replied
+    pub spawn: bool,
+}
+
+impl Command {
+    /// Usage line derived from the declared parameters and body, like
+    /// `Usage: ?play [mode=…] [edition=…] <code>`. `None` if nothing was declared
+    fn usage(&self, prefix: &str, path: &str) -> Option<String> {
+        if self.positional.is_none() && self.params.is_empty() && self.body == Body::None {
+            return None;
+        }
+
+        let mut usage = format!("Usage: `{}{}", prefix, path);
+        if let Some(positional) = self.positional {
+            usage += &format!(" [{}]", positional);
+        }
+        for param in self.params {
+            match self.defaults.iter().find(|(key, _)| key == param) {
+                Some((_, default)) => usage += &format!(" [{}={}]", param, default),
+                None => usage += &format!(" [{}=…]", param),
+            }
+        }
+        match self.body {
+            Body::None => {}
+            Body::Optional(name) => usage += &format!(" [{}]", name),
+            Body::Required(name) => usage += &format!(" <{}>", name),
+        }
+        usage.push('`');
+        Some(usage)
+    }
+
+    /// The usage line and the example, shown below the help of the command. `None` if it has
+    /// neither
+    fn help_footer(&self, prefix: &str, path: &str) -> Option<String> {
+        // Examples are written with `?`
+        let example = self.example.map(|example| match example.strip_prefix('?') {
+            Some(example) => format!("Example: `{}{}`", prefix, example),
+            None => format!("Example: `{}`", example),
+        });
+        let lines: Vec<_> = self
+            .usage(prefix, path)
+            .into_iter()
+            .chain(example)
+            .collect();
+        Some(lines.join("\n\n")).filter(|footer| !footer.is_empty())
+    }
+
+    fn new(
+        name: impl Into<Cow<'static, str>>,
+        inline_help: &'static str,
+        handler: CommandHandler,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            aliases: &[],
+            category: None,
+            guard: None,
+            soft_guard: None,
+            guard_label: None,
+            guard_cache: None,
+            broadcast_typing: false,
+            inline_help,
+            example: None,
+            positional: None,
+            params: &[],
+            case_insensitive_params: false,
+            lenient_params: false,
+            quoted_values: false,
+            defaults: &[],
+            body: Body::None,
+            handler,
+            directives: None,
+            reject_duplicate_params: false,
+            validators: Vec::new(),
+            param_types: &[],
+            reply_ttl: None,
+            skip_rerun_on_edit: false,
+            timeout: None,
+            confirmation: None,
+            private_reply: false,
+            reference_reply: false,
+            allow_mass_mentions: false,
+            cooldown: None,
+            max_argument_length: MAX_ARGUMENT_LENGTH,
+            truncate_long_arguments: false,
+            quiet: false,
+            spawn: false,
+        }
+    }
+}
+
+/// See `Args::shard_count`
+fn cached_shard_count(cache: &Cache) -> u64 {
+    cache.shard_count.max(1)
+}
+
+/// See `Args::guild_count`
+fn cached_guild_count(cache: &Cache) -> usize {
+    cache.guilds.len() + cache.unavailable_guilds.len()
+}
+
+/// See `Args::param_in_range`
+fn parse_in_range<T>(key: &str, value: &str, min: T, max: T) -> Result<T, Error>
+where
+    T: PartialOrd + FromStr + Display,
+{
+    let value = value
+        .parse::<T>()
+        .map_err(|_| CommandError::User(format!("argument `{}` must be a number", key)))?;
+    // Written this way round so that NaN is rejected too
+    if !(value >= min && value <= max) {
+        return Err(CommandError::User(format!(
+            "argument `{}` must be between {} and {}",
+            key, min, max
+        ))
+        .into());
+    }
+    Ok(value)
+}
+
+/// See `Args::approximate_user_count`
+fn cached_user_count(cache: &Cache) -> u64 {
+    let member_count = cache
+        .guilds
+        .values()
+        .map(|guild| guild.read().member_count)
+        .sum::<u64>();
+    // The member counts are missing until the guilds are fully received
+    member_count.max(cache.users.len() as u64)
+}
+
+/// Extracts directives from a command body, returning them as key-value pairs along with the body
+/// with the directives removed
+pub type DirectiveParser = fn(&str) -> (Vec<(&str, &str)>, String);
+
+/// Building block for a `DirectiveParser`: extracts `// key: value` comment lines from the body,
+/// but only for the given keys so that regular comments stay in the code
+pub fn comment_directives<'a>(body: &'a str, keys: &[&str]) -> (Vec<(&'a str, &'a str)>, String) {
+    let mut directives = Vec::new();
+    let mut cleaned_body = String::new();
+    for line in body.lines() {
+        let directive = line.trim().strip_prefix("//").and_then(|comment| {
+            let mut splitn_2 = comment.splitn(2, ':');
+            let key = splitn_2.next()?.trim();
+            let value = splitn_2.next()?.trim();
+            Some((key, value)).filter(|_| keys.contains(&key))
+        });
+        match directive {
+            Some(directive) => directives.push(directive),
+            None => {
+                cleaned_body.push_str(line);
+                cleaned_body.push('\n');
+            }
+        }
+    }
+
+    if directives.is_empty() {
+        // Leave the body exactly as it was
+        cleaned_body = body.to_owned();
+    }
+    (directives, cleaned_body)
 }
 
+#[derive(Clone)]
 pub struct Args<'a> {
     pub http: &'a HttpClient,
     pub cx: &'a Context,
     pub msg: &'a Message,
+    /// The name the command was invoked with, e.g. an alias, and for subcommands the names of the
+    /// groups before it, like `tags add`. Arguments aren't included
+    pub command: &'a str,
+    /// Values of the key-value parameters and the positional argument. They never contain
+    /// whitespace, since that's what the message is split on
     pub params: HashMap<&'a str, &'a str>,
+    /// All key-value parameters in the order they were given, including repeated keys. `params`
+    /// only holds the last value of each key
+    pub params_in_order: Vec<(&'a str, &'a str)>,
+    /// Everything after the parameters, without leading and trailing whitespace. Whitespace inside
+    /// is kept as it was, since it matters for code
     pub body: &'a str,
+    /// The message text following the command name, before it was split into parameters and
+    /// body, for commands that parse their arguments themselves
+    pub raw_args: &'a str,
+    /// Send replies to the author in a DM instead of to the channel, see `Command::private_reply`
+    pub private_reply: bool,
+    /// Send replies as Discord replies to the message, see `Command::reference_reply`
+    pub reference_reply: bool,
+    /// Let replies ping roles, `@everyone` and `@here`, see `Command::allow_mass_mentions`
+    pub allow_mass_mentions: bool,
+    /// The prefix that starts commands where the message was sent, `?` unless the guild has a
+    /// prefix of its own. For replies that tell how to use a command
+    pub prefix: &'a str,
+    /// Appended to text replies, see `GuardOutcome::AllowWithNotice`
+    pub notice: Option<&'a str>,
+    /// Appended to the text reply of a help handler, see `Command::help_footer`
+    pub help_footer: Option<&'a str>,
+    /// See `data`
+    shared_data: &'a Arc<SharedData>,
 }
 
-pub struct Commands {
-    client: HttpClient,
-    commands: Vec<Command>,
-}
+impl<'a> Args<'a> {
+    /// The state shared by all commands, see `Commands::set_data`. `None` if it isn't of type `T`
+    pub fn data<T: Any>(&self) -> Option<&'a T> {
+        let shared_data: &'a SharedData = &**self.shared_data;
+        shared_data.downcast_ref()
+    }
 
-impl Commands {
-    pub fn new() -> Self {
-        Self {
-            client: HttpClient::new(),
-            commands: vec![Command {
-                name: "help",
-                aliases: &[],
-                broadcast_typing: false,
-                inline_help: "Show this menu",
-                handler: CommandHandler::Help,
-            }],
+    /// Get the value of a parameter, failing with a user-facing error if it wasn't given
+    pub fn param(&self, key: &str) -> Result<&'a str, Error> {
+        match self.params.get(key) {
+            Some(value) => Ok(value),
+            None => {
+                Err(CommandError::User(Locale::of(self).text(Text::MissingArgument(key))).into())
+            }
         }
     }
 
-    pub fn add(
-        &mut self,
-        command: &'static str,
-        handler: impl Fn(&Args) -> Result<(), Error> + Send + Sync + 'static,
-        inline_help: &'static str,
-        long_help: impl Fn(&Args) -> Result<(), Error> + Send + Sync + 'static,
-    ) -> &mut Command {
-        self.commands.push(Command {
-            name: command,
-            aliases: &[],
-            broadcast_typing: false,
-            inline_help,
-            handler: CommandHandler::Custom {
-                action: Box::new(handler),
-                help: Box::new(long_help),
-            },
-        });
-        self.commands.last_mut().unwrap()
+    /// Split a comma-separated parameter value (`roles=mod,helper,vip`) into its items. Items are
+    /// trimmed, and empty ones, e.g. from a trailing comma, are dropped. Returns an empty list if
+    /// the parameter wasn't given.
+    pub fn param_list(&self, key: &str) -> Vec<&'a str> {
+        split_list(self.params.get(key).copied())
     }
 
-    pub fn help_menu(&self, args: &Args) -> Result<(), Error> {
-        if args.body.is_empty() {
-            let mut menu = "```\nCommands:\n".to_owned();
-            for command in &self.commands {
-                menu += &format!("\t?{:<12}{}\n", command.name, command.inline_help);
-            }
-            menu += "\nType ?help command for more info on a command.";
-            menu += "\nYou can edit your message to the bot and the bot will edit its response.";
-            menu += "\n```";
+    /// Get every value given for a parameter that may be repeated, like `option=a option=b`
+    pub fn param_all(&self, key: &str) -> Vec<&'a str> {
+        self.params_in_order
+            .iter()
+            .filter(|(param_name, _)| *param_name == key)
+            .map(|(_, param_val)| *param_val)
+            .collect()
+    }
 
-            crate::api::send_reply(args, &menu)
-        } else {
-            match self.find_command(&args.body) {
-                Some(cmd) => match &cmd.handler {
-                    CommandHandler::Help => crate::api::send_reply(args, "Are you beyond help?"),
-                    CommandHandler::Custom { help, .. } => (help)(args),
-                },
-                None => crate::api::send_reply(args, &format!("No such command `{}`", args.body)),
-            }
-        }
+    /// Parse a user mention parameter (`<@123>`, or `<@!123>` for nicknamed members). Like the
+    /// other `param_*` helpers, this works for the positional argument and for key-value
+    /// parameters like `user=@someone` alike, both end up in `params`
+    pub fn param_user(&self, key: &str) -> Result<UserId, Error> {
+        parse_user(key, self.param(key)?)
     }
 
-    fn find_command<'a>(&'a self, command_name: &str) -> Option<&'a Command> {
-        self.commands.iter().find(|cmd| {
-            let command_matches = cmd.name.eq_ignore_ascii_case(command_name);
-            let alias_matches = cmd
-                .aliases
-                .iter()
-                .any(|alias| alias.eq_ignore_ascii_case(command_name));
-            command_matches || alias_matches
-        })
+    /// Parse a channel mention parameter (`<#123>`)
+    pub fn param_channel(&self, key: &str) -> Result<ChannelId, Error> {
+        parse_channel(key, self.param(key)?)
     }
 
-    pub fn execute(&self, cx: &Context, serenity_msg: &Message) {
-        // find the first matching prefix and strip it
-        let msg = match PREFIXES
+    /// Parse a role mention parameter (`<@&123>`)
+    pub fn param_role(&self, key: &str) -> Result<RoleId, Error> {
+        parse_role(key, self.param(key)?)
+    }
+
+    /// Parse a duration parameter like `90s`, `15m`, `2h`, `1d` or `1h30m`
+    pub fn param_duration(&self, key: &str) -> Result<Duration, Error> {
+        parse_duration_param(key, self.param(key)?)
+    }
+
+    /// Parse a whole number parameter
+    pub fn param_int(&self, key: &str) -> Result<i64, Error> {
+        parse_int(key, self.param(key)?)
+    }
+
+    /// Number of shards the bot runs on, at least 1
+    pub fn shard_count(&self) -> u64 {
+        cached_shard_count(&self.cx.cache.read())
+    }
+
+    /// Number of guilds the bot is in, including the ones that are currently unavailable and
+    /// therefore not in the cache
+    pub fn guild_count(&self) -> usize {
+        cached_guild_count(&self.cx.cache.read())
+    }
+
+    /// Total member count of all available guilds. Members of several guilds are counted once per
+    /// guild, so this is only an approximation of how many users the bot reaches
+    pub fn approximate_user_count(&self) -> u64 {
+        cached_user_count(&self.cx.cache.read())
+    }
+
+    /// Parse a numeric parameter and check that it's within `min..=max`
+    pub fn param_in_range<T>(&self, key: &str, min: T, max: T) -> Result<T, Error>
+    where
+        T: PartialOrd + FromStr + Display,
+    {
+        parse_in_range(key, self.param(key)?, min, max)
+    }
+
+    /// Post a status message, like `Fetching…`, that can be updated while the command works. The
+    /// final `api::send_reply` replaces it with the result. Commands with private replies get no
+    /// status message, the DM with the result is all the author sees
+    pub fn progress(&self, text: &str) -> Result<crate::api::Progress, Error> {
+        crate::api::start_progress(self, text)
+    }
+
+    /// Download the files attached to the message, in the order they were attached
+    pub fn download_attachments(&self) -> Result<Vec<Vec<u8>>, Error> {
+        self.msg
+            .attachments
             .iter()
-            .find_map(|prefix| serenity_msg.content.strip_prefix(prefix))
-        {
-            Some(x) => x,
-            None => return,
+            .map(|attachment| download_attachment(self.http, attachment))
+            .collect()
+    }
+}
+
+/// Download an attachment, making sure it arrived completely. A flaky connection can cut the
+/// download short, so it's retried once before giving up
+fn download_attachment(http: &HttpClient, attachment: &Attachment) -> Result<Vec<u8>, Error> {
+    download_complete(&attachment.filename, attachment.size, || {
+        Ok(http
+            .get(&attachment.url)
+            .send()?
+            .error_for_status()?
+            .bytes()?
+            .to_vec())
+    })
+}
+
+/// Run `download` until it got the declared `size` bytes of the file `filename`, at most twice
+fn download_complete(
+    filename: &str,
+    size: u64,
+    mut download: impl FnMut() -> Result<Vec<u8>, Error>,
+) -> Result<Vec<u8>, Error> {
+    let mut bytes = download()?;
+    if bytes.len() as u64 != size {
+        warn!("Incomplete download of attachment {}, retrying", filename);
+        bytes = download()?;
+    }
+    if bytes.len() as u64 != size {
+        return Err(format!(
+            "download of `{}` was incomplete ({} of {} bytes)",
+            filename,
+            bytes.len(),
+            size
+        )
+        .into());
+    }
+    Ok(bytes)
+}
+
+/// The items of a comma-separated list, see `Args::param_list`
+fn split_list(value: Option<&str>) -> Vec<&str> {
+    value
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Parse the value of the parameter `key` as a user mention, see `Args::param_user`
+fn parse_user(key: &str, mention: &str) -> Result<UserId, Error> {
+    parse_mention(key, mention, "user", &["<@!", "<@"]).map(UserId)
+}
+
+/// Parse the value of the parameter `key` as a channel mention, see `Args::param_channel`
+fn parse_channel(key: &str, mention: &str) -> Result<ChannelId, Error> {
+    parse_mention(key, mention, "channel", &["<#"]).map(ChannelId)
+}
+
+/// Parse the value of the parameter `key` as a role mention, see `Args::param_role`
+fn parse_role(key: &str, mention: &str) -> Result<RoleId, Error> {
+    parse_mention(key, mention, "role", &["<@&"]).map(RoleId)
+}
+
+/// Parse the value of the parameter `key` as a duration, see `Args::param_duration`
+fn parse_duration_param(key: &str, value: &str) -> Result<Duration, Error> {
+    parse_duration(value)
+        .map_err(|e| CommandError::User(format!("invalid argument `{}`: {}", key, e)).into())
+}
+
+/// Parse the value of the parameter `key` as a whole number, see `Args::param_int`
+fn parse_int(key: &str, value: &str) -> Result<i64, Error> {
+    value
+        .parse()
+        .map_err(|_| CommandError::User(format!("argument `{}` must be a number", key)).into())
+}
+
+/// Extract the ID from a Discord mention, trying each of the given mention prefixes
+fn parse_mention(key: &str, mention: &str, kind: &str, prefixes: &[&str]) -> Result<u64, Error> {
+    prefixes
+        .iter()
+        .find_map(|prefix| {
+            mention
+                .strip_prefix(prefix)?
+                .strip_suffix('>')?
+                .parse()
+                .ok()
+        })
+        .ok_or_else(|| {
+            CommandError::User(format!("argument `{}` must be a {} mention", key, kind)).into()
+        })
+}
+
+/// Parse a human-readable duration made up of one or more `<amount><unit>` parts, e.g. `1h30m`.
+/// Supported units are `s`, `m`, `h` and `d`
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    if text.starts_with('-') {
+        return Err("durations can't be negative".to_owned());
+    }
+    if text.is_empty() {
+        return Err("empty duration".to_owned());
+    }
+
+    let mut total_secs: u64 = 0;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let amount_end = rest.find(|c: char| !c.is_ascii_digit()).ok_or_else(|| {
+            format!(
+                "missing unit in duration `{}`, use s, m, h or d (e.g. `15m`)",
+                text
+            )
+        })?;
+        let amount = rest[..amount_end]
+            .parse::<u64>()
+            .map_err(|_| format!("invalid duration `{}`", text))?;
+
+        let mut chars = rest[amount_end..].chars();
+        let unit_secs = match chars.next() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 60 * 60,
+            Some('d') => 24 * 60 * 60,
+            _ => {
+                return Err(format!(
+                    "invalid duration `{}`, use s, m, h or d as units",
+                    text
+                ))
+            }
         };
 
-        // Find the command that matches this message
-        let (command_name, msg) =
-            msg.split_at(msg.find(char::is_whitespace).unwrap_or_else(|| msg.len()));
-        let msg = msg.trim();
-        let command = match self.find_command(command_name) {
-            Some(x) => x,
-            None => return,
+        total_secs = amount
+            .checked_mul(unit_secs)
+            .and_then(|secs| total_secs.checked_add(secs))
+            .ok_or_else(|| format!("duration `{}` is too long", text))?;
+        rest = chars.as_str();
+    }
+
+    Ok(Duration::from_secs(total_secs))
+}
+
+/// Default for `Commands::slow_command_threshold`
+const SLOW_COMMAND_THRESHOLD: Duration = Duration::from_secs(10);
+
+/// Default for `Commands::dedup_window`
+const DEDUP_WINDOW: Duration = Duration::from_secs(30);
+
+/// Default for `Commands::dedup_capacity`
+const DEDUP_CAPACITY: usize = 1000;
+
+/// Default for `Command::max_argument_length`, as long as a message with Nitro may be, so that by
+/// default no message is refused for its length alone
+const MAX_ARGUMENT_LENGTH: usize = 4000;
+
+/// How long the author of a command has to confirm it, see `Command::confirmation`
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long requests of the default HTTP client may take, see `Commands::new`
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The `User-Agent` of the default HTTP client, some APIs reject requests without a proper one
+const HTTP_USER_AGENT: &str = "rust-lang/discord-mods-bot";
+
+/// How long a page of the help menu may get, comfortably within the limit of an embed
+const HELP_PAGE_LENGTH: usize = 1500;
+
+/// Base URL of the Discord API, for the endpoints serenity doesn't cover yet
+const DISCORD_API: &str = "https://discord.com/api/v8";
+
+/// How long identical error replies to the same channel are suppressed after the first one
+const ERROR_REPLY_THROTTLE: Duration = Duration::from_secs(60);
+
+/// The last error reply sent to a channel
+struct RecentError {
+    text: String,
+    sent_at: Instant,
+    /// How many identical replies were suppressed since
+    suppressed: usize,
+}
+
+/// Description of a registered command for external use, like generated documentation, see
+/// `Commands::manifest`
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct CommandInfo {
+    pub name: String,
+    pub aliases: Vec<&'static str>,
+    pub description: &'static str,
+    pub category: Option<&'static str>,
+    pub usage: Option<String>,
+    pub example: Option<&'static str>,
+    /// Whether a guard restricts who may use the command
+    pub guarded: bool,
+    pub guard_label: Option<&'static str>,
+    /// Whether the command is enabled, see `Commands::disable`. Subcommands are disabled along
+    /// with their group
+    pub enabled: bool,
+    pub cooldown_secs: Option<u64>,
+    /// For groups, the commands in the group
+    pub subcommands: Vec<CommandInfo>,
+}
+
+impl CommandInfo {
+    fn of(command: &Command, path: &str) -> Self {
+        let subcommands = match &command.handler {
+            CommandHandler::Group(group) => group
+                .subcommands
+                .iter()
+                .map(|subcommand| {
+                    CommandInfo::of(subcommand, &format!("{} {}", path, subcommand.name))
+                })
+                .collect(),
+            _ => Vec::new(),
         };
+        Self {
+            name: command.name.to_string(),
+            aliases: command.aliases.to_vec(),
+            description: command.inline_help,
+            category: command.category,
+            usage: command.usage("?", path),
+            example: command.example,
+            guarded: command.guard.is_some(),
+            guard_label: command.guard_label,
+            enabled: true,
+            cooldown_secs: command.cooldown.as_ref().map(|c| c.duration.as_secs()),
+            subcommands,
+        }
+    }
 
-        let mut params = HashMap::new();
-        let mut body = "";
-        for token in msg.split_whitespace() {
-            let mut splitn_2 = token.splitn(2, '=');
-            if let (Some(param_name), Some(param_val)) = (splitn_2.next(), splitn_2.next()) {
-                // Check that the param key is sensible, otherwise any equal sign in arg body
-                // (think ?eval) will be parsed as a parameter
-                if param_name.chars().all(|c| c.is_alphanumeric()) {
-                    params.insert(param_name, param_val);
-                    continue;
-                }
-            }
-            // If this whitespace-separated token is not a "key=value" pair, this must
-            // be the beginning of the command body. So, let's find out where we are within
-            // the msg string and set the body accordingly
-            let body_start = token.as_ptr() as usize - msg.as_ptr() as usize;
-            body = &msg[body_start..];
-            break;
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        for subcommand in &mut self.subcommands {
+            subcommand.set_enabled(enabled);
         }
+    }
 
-        let args = Args {
-            body,
-            params,
-            cx: &cx,
-            msg: &serenity_msg,
-            http: &self.client,
+    /// Append a line about the command and one for each of its subcommands to `report`, see
+    /// `Commands::describe`
+    fn describe(&self, path: &str, report: &mut String) {
+        let path = format!("{}{}", path, self.name);
+        *report += &format!("?{}", path);
+        if !self.aliases.is_empty() {
+            *report += &format!(" (aliases: {})", self.aliases.join(", "));
+        }
+        *report += &format!(" [{}]", self.category.unwrap_or("uncategorized"));
+        match (self.guarded, self.guard_label) {
+            (true, Some(label)) => *report += &format!(", guard: {}", label),
+            (true, None) => *report += ", guarded",
+            (false, _) => {}
+        }
+        if let Some(secs) = self.cooldown_secs {
+            *report += &format!(", cooldown: {}s", secs);
+        }
+        if !self.enabled {
+            *report += ", disabled";
+        }
+        report.push('\n');
+        for subcommand in &self.subcommands {
+            subcommand.describe(&format!("{} ", path), report);
+        }
+    }
+}
+
+/// Size of the command registry, see `Commands::stats`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegistryStats {
+    /// Top-level commands, including groups
+    pub commands: usize,
+    /// Commands inside groups
+    pub subcommands: usize,
+    pub aliases: usize,
+    pub categories: usize,
+    /// Names and aliases that are already taken by another command of the same level, so that
+    /// they can never be invoked
+    pub shadowed: Vec<String>,
+}
+
+/// The prefixes guilds use along with `?`, see `Commands::guild_prefixes`. Clones share the
+/// prefixes
+#[derive(Clone, Default)]
+pub struct GuildPrefixes {
+    prefixes: Arc<RwLock<HashMap<GuildId, String>>>,
+    /// Where the prefixes are saved, see `store_in`
+    file: Arc<RwLock<Option<PathBuf>>>,
+}
+
+impl GuildPrefixes {
+    pub fn get(&self, guild_id: GuildId) -> Option<String> {
+        self.prefixes.read().get(&guild_id).cloned()
+    }
+
+    /// Accept `prefix` as well as `?` in the guild, or only `?` again with `None`. The prefix can't
+    /// contain whitespace. Fails if the prefixes can't be saved, the new one is used until the bot
+    /// restarts anyway
+    pub fn set(&self, guild_id: GuildId, prefix: Option<String>) -> io::Result<()> {
+        let mut prefixes = self.prefixes.write();
+        match prefix {
+            Some(prefix) => prefixes.insert(guild_id, prefix),
+            None => prefixes.remove(&guild_id),
         };
+        match &*self.file.read() {
+            Some(path) => save_prefixes(path, &prefixes),
+            None => Ok(()),
+        }
+    }
 
-        if command.broadcast_typing {
-            if let Err(e) = serenity_msg.channel_id.broadcast_typing(&cx.http) {
-                warn!("Can't broadcast typing: {}", e);
+    /// Load the prefixes from `path` if it exists, and save them there whenever one changes, so
+    /// that they survive restarts. Without this they're only kept in memory. The file has one line
+    /// per guild, with the guild ID and the prefix separated by a tab
+    pub fn store_in(&self, path: impl Into<PathBuf>) -> io::Result<()> {
+        let path = path.into();
+        match std::fs::read_to_string(&path) {
+            Ok(content) => {
+                let mut prefixes = self.prefixes.write();
+                for line in content.lines() {
+                    let entry = line.split_once('\t').and_then(|(guild_id, prefix)| {
+                        Some((GuildId(guild_id.parse().ok()?), prefix.to_owned()))
+                    });
+                    match entry {
+                        Some((guild_id, prefix)) => {
+                            prefixes.insert(guild_id, prefix);
+                        }
+                        None => warn!("Ignoring malformed line in {}: {}", path.display(), line),
+                    }
+                }
             }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
         }
+        *self.file.write() = Some(path);
+        Ok(())
+    }
+}
 
-        let command_execution_result = match &command.handler {
-            CommandHandler::Help => self.help_menu(&args),
-            CommandHandler::Custom { action, .. } => (action)(&args),
-        };
-        if let Err(e) = command_execution_result {
-            error!("Error when executing command {}: {}", command.name, e);
-            if let Err(e) = crate::api::send_reply(&args, &e.to_string()) {
-                error!("{}", e)
-            }
+/// Write the prefixes to a temporary file and move it over `path`, so that a crash while writing
+/// doesn't lose the prefixes saved before
+fn save_prefixes(path: &Path, prefixes: &HashMap<GuildId, String>) -> io::Result<()> {
+    let mut content = String::new();
+    for (guild_id, prefix) in prefixes {
+        content += &format!("{}\t{}\n", guild_id, prefix);
+    }
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, content)?;
+    std::fs::rename(temporary, path)
+}
+
+/// Where a command may be used, see `Commands::restrict_to_channels`
+#[derive(Clone, Debug)]
+pub struct ChannelRestriction {
+    channels: HashSet<ChannelId>,
+    /// Whether `channels` are the only ones the command is allowed in, or the ones it's denied in
+    allow_listed: bool,
+    /// Whether to tell the author why the command didn't run, instead of ignoring it silently
+    reply: bool,
+}
+
+impl ChannelRestriction {
+    fn permits(&self, channel_id: ChannelId) -> bool {
+        self.channels.contains(&channel_id) == self.allow_listed
+    }
+
+    fn denial(&self, locale: Locale) -> String {
+        if self.allow_listed {
+            let mut channels: Vec<_> = self.channels.iter().map(|id| id.mention()).collect();
+            channels.sort();
+            locale.text(Text::OnlyInChannels(&channels.join(", ")))
+        } else {
+            locale.text(Text::NotInThisChannel)
         }
     }
 }
+
+/// An immutable copy of the registered commands, see `Commands::snapshot`. Cheap to hold as the
+/// commands themselves are shared with the registry. The bot doesn't reload its commands, so
+/// this is only built for tests until it does
+#[cfg(test)]
+#[derive(Clone)]
+pub struct CommandsSnapshot {
+    commands: Vec<Arc<Command>>,
+}
+
+pub struct Commands {
+    /// Commands taking longer than this are logged as warnings
+    pub slow_command_threshold: Duration,
+    /// Ignore messages of other bots, so that bots can't trigger each other in a loop. On by
+    /// default, turn it off for bots that are meant to use commands
+    pub ignore_bots: bool,
+    /// Also run commands whose prefix is in the middle of a message, like `hey bot, ?roll 2d6`.
+    /// Only the first prefix that starts a word is considered, and the message has to name a
+    /// command right after it, a lone `?` doesn't open the help menu. Off by default, since every
+    /// message is then scanned for all prefixes at every position, instead of only at its start
+    pub match_anywhere: bool,
+    /// A message delivered again within this long after its first delivery is ignored, mobile
+    /// clients sometimes send the same message event twice
+    pub dedup_window: Duration,
+    /// How many recently executed messages are remembered for `dedup_window`, the oldest ones are
+    /// forgotten first
+    pub dedup_capacity: usize,
+    /// Reply with the most similar commands to messages like `?cratr foo` that name no command.
+    /// On by default
+    pub suggest_commands: bool,
+    client: HttpClient,
+    /// Behind a lock so that commands can be registered and removed while the bot is running
+    commands: RwLock<Vec<Arc<Command>>>,
+    before_hooks: Vec<BeforeHook>,
+    outcomes: Arc<Outcomes>,
+    /// Cached guard results by full command name, like `config prefix`, and user, with their expiry
+    guard_results: Mutex<HashMap<(String, UserId), (bool, Instant)>>,
+    /// When the cooldowns of commands, by full command name, end for a user
+    cooldowns: Mutex<HashMap<(String, UserId), Instant>>,
+    /// When the cooldowns of commands, by full command name, end in a channel, see
+    /// `Cooldown::channel_duration`
+    channel_cooldowns: Mutex<HashMap<(String, ChannelId), Instant>>,
+    /// By lowercased top-level command name
+    channel_restrictions: RwLock<HashMap<String, ChannelRestriction>>,
+    /// Lowercased names of the disabled top-level commands, with whether to reply that they are
+    disabled: RwLock<HashMap<String, bool>>,
+    /// Lowercased names of the top-level commands available in a guild, by guild. The set for
+    /// `None` is for DMs and guilds without a set of their own, see `set_guild_commands`
+    guild_commands: RwLock<HashMap<Option<GuildId>, HashSet<String>>>,
+    banned_users: RwLock<HashSet<UserId>>,
+    guild_prefixes: GuildPrefixes,
+    shared_data: Arc<SharedData>,
+    /// When the recently executed messages were first seen, oldest first
+    recent_messages: Mutex<IndexMap<MessageId, Instant>>,
+}
+
+impl Commands {
+    /// Commands sharing an HTTP client with a timeout of `HTTP_TIMEOUT` and the bot's user agent
+    pub fn new() -> Self {
+        let client = HttpClient::builder()
+            .timeout(HTTP_TIMEOUT)
+            .user_agent(HTTP_USER_AGENT)
+            .build()
+            .expect("Can't build the HTTP client");
+        Self::with_http_client(client)
+    }
+
+    /// Commands sharing `client` for their requests, see `Args::http`. Use this to configure e.g.
+    /// a proxy or different timeouts
+    pub fn with_http_client(client: HttpClient) -> Self {
+        Self {
+            slow_command_threshold: SLOW_COMMAND_THRESHOLD,
+            ignore_bots: true,
+            match_anywhere: false,
+            dedup_window: DEDUP_WINDOW,
+            dedup_capacity: DEDUP_CAPACITY,
+            suggest_commands: true,
+            client,
+            commands: RwLock::new(vec![Arc::new(Command::new(
+                "help",
+                "Show this menu",
+                CommandHandler::Help,
+            ))]),
+            before_hooks: Vec::new(),
+            outcomes: Arc::new(Outcomes {
+                after_hooks: Vec::new(),
+                audit_sinks: Vec::new(),
+                metrics: Arc::new(Metrics::default()),
+                recent_errors: Mutex::new(HashMap::new()),
+            }),
+            guard_results: Mutex::new(HashMap::new()),
+            cooldowns: Mutex::new(HashMap::new()),
+            channel_cooldowns: Mutex::new(HashMap::new()),
+            channel_restrictions: RwLock::new(HashMap::new()),
+            disabled: RwLock::new(HashMap::new()),
+            guild_commands: RwLock::new(HashMap::new()),
+            banned_users: RwLock::new(HashSet::new()),
+            guild_prefixes: GuildPrefixes::default(),
+            shared_data: Arc::new(()),
+            recent_messages: Mutex::new(IndexMap::new()),
+        }
+    }
+
+    /// Usage counters of every command that was run, keyed by the top-level command name
+    pub fn metrics_snapshot(&self) -> HashMap<String, CommandStats> {
+        self.outcomes.metrics.snapshot()
+    }
+
+    /// Serve the usage counters and latencies of the commands in the Prometheus text format at
+    /// `http://<addr>/metrics`, on a thread of its own
+    #[cfg(feature = "metrics-server")]
+    pub fn serve_metrics(&self, addr: impl std::net::ToSocketAddrs) -> std::io::Result<()> {
+        crate::metrics::serve(Arc::clone(&self.outcomes.metrics), addr)
+    }
+
+    /// Make `data` available to all commands through `Args::data`, e.g. a database connection pool
+    /// or the configuration
+    pub fn set_data(&mut self, data: impl Any + Send + Sync) {
+        self.shared_data = Arc::new(data);
+    }
+
+    /// The prefixes of the guilds that added one of their own. `?` and the other prefixes, like
+    /// mentioning the bot, keep working there. See `GuildPrefixes::store_in` to keep them across
+    /// restarts
+    pub fn guild_prefixes(&self) -> GuildPrefixes {
+        self.guild_prefixes.clone()
+    }
+
+    /// Silently ignore every message of these users, replacing the previously banned users
+    pub fn set_banned_users(&self, users: HashSet<UserId>) {
+        *self.banned_users.write() = users;
+    }
+
+    /// The users set with `set_banned_users`
+    pub fn banned_users(&self) -> HashSet<UserId> {
+        self.banned_users.read().clone()
+    }
+
+    /// Disable the top-level command named `root` until it's enabled again, e.g. because it
+    /// misbehaves. A disabled command is hidden from the help menu and ignored as if it wasn't
+    /// registered, unless `reply` is set, then the author is told that it's disabled
+    pub fn disable(&self, root: &str, reply: bool) {
+        self.disabled
+            .write()
+            .insert(root.to_ascii_lowercase(), reply);
+    }
+
+    /// Enable a command disabled with `disable`, returning whether it was disabled
+    pub fn enable(&self, root: &str) -> bool {
+        self.disabled
+            .write()
+            .remove(&root.to_ascii_lowercase())
+            .is_some()
+    }
+
+    pub fn is_enabled(&self, root: &str) -> bool {
+        !self
+            .disabled
+            .read()
+            .contains_key(&root.to_ascii_lowercase())
+    }
+
+    /// Only make the top-level commands named `roots` available in the guild, or with `None`, in
+    /// DMs and the guilds without a set of their own. Other commands are ignored there as if they
+    /// weren't registered. The help menu is always available. Replaces any earlier set
+    pub fn set_guild_commands(&self, guild_id: Option<GuildId>, roots: &[&str]) {
+        let roots = roots.iter().map(|root| root.to_ascii_lowercase()).collect();
+        self.guild_commands.write().insert(guild_id, roots);
+    }
+
+    /// Make all commands available in the guild again, or with `None`, in DMs and the guilds
+    /// without a set of their own. Returns whether there was a set
+    pub fn clear_guild_commands(&self, guild_id: Option<GuildId>) -> bool {
+        self.guild_commands.write().remove(&guild_id).is_some()
+    }
+
+    /// The prefix that starts commands in the guild, see `guild_prefixes`
+    fn prefix_of(&self, guild_id: Option<GuildId>) -> String {
+        guild_id
+            .and_then(|guild_id| self.guild_prefixes.get(guild_id))
+            .unwrap_or_else(|| "?".to_owned())
+    }
+
+    /// Whether the top-level command is available in the guild, see `set_guild_commands`
+    fn is_available(&self, command: &Command, guild_id: Option<GuildId>) -> bool {
+        if matches!(command.handler, CommandHandler::Help) {
+            return true;
+        }
+        let guild_commands = self.guild_commands.read();
+        let commands = guild_id
+            .and_then(|guild_id| guild_commands.get(&Some(guild_id)))
+            .or_else(|| guild_commands.get(&None));
+        match commands {
+            Some(commands) => commands.contains(&command.name.to_ascii_lowercase()),
+            None => true,
+        }
+    }
+
+    /// Only run the top-level command `root` and its subcommands in `channels`, and ignore them
+    /// everywhere else. If `reply` is set, the author is told where the command can be used
+    /// instead. Replaces any earlier restriction of the command
+    pub fn restrict_to_channels(
+        &self,
+        root: &str,
+        channels: impl IntoIterator<Item = ChannelId>,
+        reply: bool,
+    ) {
+        self.set_channel_restriction(root, channels, true, reply);
+    }
+
+    /// Ignore the top-level command `root` and its subcommands in `channels`, see
+    /// `restrict_to_channels`
+    pub fn block_in_channels(
+        &self,
+        root: &str,
+        channels: impl IntoIterator<Item = ChannelId>,
+        reply: bool,
+    ) {
+        self.set_channel_restriction(root, channels, false, reply);
+    }
+
+    /// Lift the channel restriction of a command, returning whether it had one
+    pub fn unrestrict_channels(&self, root: &str) -> bool {
+        self.channel_restrictions
+            .write()
+            .remove(&root.to_ascii_lowercase())
+            .is_some()
+    }
+
+    fn set_channel_restriction(
+        &self,
+        root: &str,
+        channels: impl IntoIterator<Item = ChannelId>,
+        allow_listed: bool,
+        reply: bool,
+    ) {
+        let restriction = ChannelRestriction {
+            channels: channels.into_iter().collect(),
+            allow_listed,
+            reply,
+        };
+        self.channel_restrictions
+            .write()
+            .insert(root.to_ascii_lowercase(), restriction);
+    }
+
+    /// Send a record of every command run to `sink`, including the runs its guard denied
+    pub fn audit(&mut self, sink: impl AuditSink + 'static) {
+        self.outcomes_mut().audit_sinks.push(Box::new(sink));
+    }
+
+    /// Run `hook` before every command. If it returns `Ok(false)`, the command is silently
+    /// skipped, and if it fails, the error is replied instead of running the command
+    pub fn before(&mut self, hook: impl Fn(&Args) -> Result<bool, Error> + Send + Sync + 'static) {
+        self.before_hooks.push(Box::new(hook));
+    }
+
+    /// Run `hook` after every command that was run, with the outcome of the command
+    #[allow(dead_code)] // The audit sinks and metrics do the bot's own bookkeeping
+    pub fn after(&mut self, hook: impl Fn(&Args, &Result<(), Error>) + Send + Sync + 'static) {
+        self.outcomes_mut().after_hooks.push(Box::new(hook));
+    }
+
+    fn outcomes_mut(&mut self) -> &mut Outcomes {
+        // Only the prompts of confirmed commands share them, and those need the bot running
+        Arc::get_mut(&mut self.outcomes).expect("hooks are added before the bot runs")
+    }
+
+    pub fn add(
+        &mut self,
+        command: &'static str,
+        handler: impl Fn(&Args) -> Result<(), Error> + Send + Sync + 'static,
+        inline_help: &'static str,
+        long_help: impl Fn(&Args) -> Result<(), Error> + Send + Sync + 'static,
+    ) -> &mut Command {
+        self.push(Command::new(
+            command,
+            inline_help,
+            CommandHandler::Custom {
+                action: Arc::new(handler),
+                help: Box::new(long_help),
+            },
+        ))
+    }
+
+    /// Register a command that's listed under `category` in the help menu
+    pub fn add_in_category(
+        &mut self,
+        category: &'static str,
+        command: &'static str,
+        handler: impl Fn(&Args) -> Result<(), Error> + Send + Sync + 'static,
+        inline_help: &'static str,
+        long_help: impl Fn(&Args) -> Result<(), Error> + Send + Sync + 'static,
+    ) -> &mut Command {
+        let command = self.add(command, handler, inline_help, long_help);
+        command.category = Some(category);
+        command
+    }
+
+    /// Register a command whose parameters are checked against the given patterns before it runs,
+    /// see `Command::validators`
+    #[allow(dead_code)] // No command of the bot takes parameters that need a pattern yet
+    pub fn add_validated(
+        &mut self,
+        command: &'static str,
+        handler: impl Fn(&Args) -> Result<(), Error> + Send + Sync + 'static,
+        inline_help: &'static str,
+        long_help: impl Fn(&Args) -> Result<(), Error> + Send + Sync + 'static,
+        validators: &[(&'static str, Regex)],
+    ) -> &mut Command {
+        let command = self.add(command, handler, inline_help, long_help);
+        command.validators = validators.to_vec();
+        command
+    }
+
+    /// Register a command that only runs once its author confirmed `prompt`, see
+    /// `Command::confirmation`
+    #[allow(dead_code)] // None of the bot's commands asks for confirmation yet
+    pub fn add_confirmed(
+        &mut self,
+        command: &'static str,
+        handler: impl Fn(&Args) -> Result<(), Error> + Send + Sync + 'static,
+        inline_help: &'static str,
+        long_help: impl Fn(&Args) -> Result<(), Error> + Send + Sync + 'static,
+        prompt: &'static str,
+    ) -> &mut Command {
+        let command = self.add(command, handler, inline_help, long_help);
+        command.confirmation = Some(prompt);
+        command
+    }
+
+    /// Register a command group. Subcommands are added to the returned group
+    pub fn group(&mut self, command: &'static str, inline_help: &'static str) -> &mut CommandGroup {
+        let command = self.push(Command::new(
+            command,
+            inline_help,
+            CommandHandler::Group(CommandGroup {
+                subcommands: Vec::new(),
+                default: None,
+            }),
+        ));
+        match &mut command.handler {
+            CommandHandler::Group(group) => group,
+            _ => unreachable!(),
+        }
+    }
+
+    fn push(&mut self, command: Command) -> &mut Command {
+        let commands = self.commands.get_mut();
+        commands.push(Arc::new(command));
+        // Nothing else can hold the command yet, it was only just created
+        Arc::get_mut(commands.last_mut().unwrap()).unwrap()
+    }
+
+    /// Register a command while the bot is running, e.g. a user-defined tag. Fails if there
+    /// already is a command of that name
+    #[allow(dead_code)] // For user-defined commands like tags, which the bot doesn't have yet
+    pub fn register(
+        &self,
+        command: String,
+        handler: impl Fn(&Args) -> Result<(), Error> + Send + Sync + 'static,
+        inline_help: &'static str,
+        long_help: impl Fn(&Args) -> Result<(), Error> + Send + Sync + 'static,
+    ) -> Result<(), Error> {
+        let mut commands = self.commands.write();
+        if find_command(&commands, &command).is_some() {
+            return Err(format!("command `{}` already exists", command).into());
+        }
+        commands.push(Arc::new(Command::new(
+            command,
+            inline_help,
+            CommandHandler::Custom {
+                action: Arc::new(handler),
+                help: Box::new(long_help),
+            },
+        )));
+        Ok(())
+    }
+
+    /// Remove a command while the bot is running. Returns whether there was a command of that name
+    #[allow(dead_code)] // See `register`
+    pub fn unregister(&self, command_name: &str) -> bool {
+        let mut commands = self.commands.write();
+        let count = commands.len();
+        commands.retain(|cmd| !cmd.name.eq_ignore_ascii_case(command_name));
+        commands.len() != count
+    }
+
+    /// Take a copy of the currently registered commands, e.g. to roll back to with `restore` if
+    /// reloading commands fails halfway
+    #[cfg(test)]
+    pub fn snapshot(&self) -> CommandsSnapshot {
+        CommandsSnapshot {
+            commands: self.commands.read().clone(),
+        }
+    }
+
+    /// Replace the registered commands with the ones from a snapshot
+    #[cfg(test)]
+    pub fn restore(&self, snapshot: &CommandsSnapshot) {
+        *self.commands.write() = snapshot.commands.clone();
+        self.guard_results.lock().clear();
+    }
+
+    /// Replace all commands with the ones `build` registers on a fresh `Commands`, e.g. to reload
+    /// commands defined in a config file. The new commands are swapped in at once, commands that
+    /// are running finish with the old ones. Only the commands are taken over, hooks, audit sinks
+    /// and everything else configured on the scratch `Commands` are dropped. Cached guard results
+    /// are forgotten, since the new commands may come with other guards, but running cooldowns
+    /// carry over to the new commands of the same name.
+    ///
+    /// `add` and its siblings take `&'static str` names and inline help. For names that are only
+    /// known at runtime, use `register`, which takes a `String` name. Leaking strings to make them
+    /// `'static` instead leaks them anew on every rebuild, since the old commands are freed once
+    /// the last running command is done with them, but the leaked strings never are.
+    #[allow(dead_code)] // The bot's commands are all defined in code, there's nothing to reload yet
+    pub fn rebuild(&self, build: impl FnOnce(&mut Commands)) {
+        let mut scratch = Commands::with_http_client(self.client.clone());
+        build(&mut scratch);
+        *self.commands.write() = scratch.commands.into_inner();
+        self.guard_results.lock().clear();
+    }
+
+    /// Describe every registered command, in the order they were registered. Serializes to JSON
+    /// with serde, e.g. to generate documentation
+    pub fn manifest(&self) -> Vec<CommandInfo> {
+        self.commands
+            .read()
+            .iter()
+            .map(|command| {
+                let mut info = CommandInfo::of(command, &command.name);
+                info.set_enabled(self.is_enabled(&command.name));
+                info
+            })
+            .collect()
+    }
+
+    /// List every registered command with its category, guard, cooldown and whether it's
+    /// disabled, one line each, for operators. Unlike the help menu this includes the commands
+    /// the invoking user can't run
+    pub fn describe(&self) -> String {
+        let mut report = String::new();
+        for info in self.manifest() {
+            info.describe("", &mut report);
+        }
+        report
+    }
+
+    /// Count the registered commands, and find names that can't be invoked because another
+    /// command took them first
+    pub fn stats(&self) -> RegistryStats {
+        let commands = self.commands.read();
+        let mut stats = RegistryStats {
+            commands: commands.len(),
+            ..RegistryStats::default()
+        };
+        let mut categories = HashSet::new();
+        count_names(commands.iter().map(|command| &**command), "", &mut stats);
+        for command in commands.iter() {
+            categories.extend(command.category);
+            if let CommandHandler::Group(group) = &command.handler {
+                stats.subcommands += group.subcommands.len();
+                let path = format!("{} ", command.name);
+                count_names(group.subcommands.iter(), &path, &mut stats);
+            }
+        }
+        stats.categories = categories.len();
+        stats
+    }
+
+    /// Log the size of the registry once all commands are registered, and warn about names that
+    /// can't be invoked
+    pub fn compile(&self) {
+        let stats = self.stats();
+        info!(
+            "Registered {} commands, {} subcommands and {} aliases in {} categories",
+            stats.commands, stats.subcommands, stats.aliases, stats.categories
+        );
+        for name in &stats.shadowed {
+            warn!("?{} is taken by another command and can't be invoked", name);
+        }
+    }
+
+    /// Describe the registered commands as Discord slash commands, in the JSON format of the
+    /// application commands endpoint. Serenity can't register them yet, so this is sent with a
+    /// plain HTTP request. Arguments become options of the type from `Command::param_types`, or
+    /// string options if they have none, and the commands of a group become subcommands.
+    ///
+    /// Commands that don't fit are skipped with a warning: names Discord doesn't accept, commands
+    /// with directives, which take code blocks of several lines, and groups in groups
+    pub fn to_slash_commands(&self) -> Vec<Value> {
+        self.commands
+            .read()
+            .iter()
+            .filter(|command| !matches!(command.handler, CommandHandler::Help))
+            .filter_map(|command| slash_command(command, false))
+            .collect()
+    }
+
+    /// Register the commands as the global slash commands of the bot's application, replacing
+    /// the ones registered before. See `to_slash_commands` and `execute_interaction`
+    pub fn register_slash_commands(&self, token: &str, application_id: u64) -> Result<(), Error> {
+        let url = format!("{}/applications/{}/commands", DISCORD_API, application_id);
+        let token = token.trim_start_matches("Bot ");
+        self.client
+            .put(&url)
+            .header(reqwest::header::AUTHORIZATION, format!("Bot {}", token))
+            .json(&self.to_slash_commands())
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    pub fn help_menu(&self, args: &Args) -> Result<(), Error> {
+        if args.body.is_empty() {
+            let pages = help_pages(self.visible_commands(args), args.prefix);
+            return crate::api::send_pages(args, pages, true);
+        }
+
+        // Split like `parse` does, so that whatever runs the command also shows its help
+        let (command_name, rest) = split_command_name(args.body);
+        let command = self
+            .find_top_level(command_name)
+            .filter(|cmd| self.is_visible(cmd, args));
+        if let Some(cmd) = command {
+            return self.command_help(args, &cmd, &cmd.name, rest);
+        }
+
+        // Not a command, maybe a category like `?help crates`
+        let mut commands = self.visible_commands(args);
+        commands.retain(|cmd| {
+            matches!(cmd.category, Some(category) if category.eq_ignore_ascii_case(args.body))
+        });
+        if commands.is_empty() {
+            let reply = Locale::of(args).text(Text::NoSuchCommand(args.body));
+            return crate::api::send_reply(args, &reply);
+        }
+        crate::api::send_pages(args, help_pages(commands, args.prefix), true)
+    }
+
+    /// The commands the author of the message can see in the help menu, sorted by category and
+    /// name
+    fn visible_commands(&self, args: &Args) -> Vec<Arc<Command>> {
+        // Copy the list out, so that the lock isn't held while the guards run
+        let mut commands = self.commands.read().clone();
+        commands.retain(|cmd| self.is_visible(cmd, args));
+        // Uncategorized commands come first
+        commands.sort_by(|a, b| (a.category, &a.name).cmp(&(b.category, &b.name)));
+        commands
+    }
+
+    /// Whether the top-level command is shown to the author of the message in the help menu
+    fn is_visible(&self, command: &Command, args: &Args) -> bool {
+        self.is_enabled(&command.name)
+            && self.is_available(command, args.msg.guild_id)
+            && self.is_allowed(command, &command.name, args)
+    }
+
+    /// Show the help of a command, or of the subcommand named by `rest` if it's a group. `path` is
+    /// the full name of the command, including the names of the groups it's in
+    fn command_help(
+        &self,
+        args: &Args,
+        command: &Command,
+        path: &str,
+        rest: &str,
+    ) -> Result<(), Error> {
+        match &command.handler {
+            CommandHandler::Help => crate::api::send_reply(args, "Are you beyond help?"),
+            CommandHandler::Custom { help, .. } => {
+                // The help sends its own reply, the usage line and example go below it
+                let footer = command.help_footer(args.prefix, path);
+                help(&Args {
+                    help_footer: footer.as_deref(),
+                    ..args.clone()
+                })
+            }
+            CommandHandler::Group(group) if rest.is_empty() => {
+                crate::api::send_reply(args, &group.help(args.prefix, path))
+            }
+            CommandHandler::Group(group) => {
+                let (subcommand_name, rest) = split_command_name(rest);
+                let subcommand = find_command(&group.subcommands, subcommand_name)
+                    .map(|cmd| (cmd, format!("{} {}", path, cmd.name)))
+                    .filter(|(cmd, path)| self.is_allowed(cmd, path, args));
+                match subcommand {
+                    Some((subcommand, path)) => self.command_help(args, subcommand, &path, rest),
+                    None => crate::api::send_reply(
+                        args,
+                        &Locale::of(args).text(Text::NoSuchCommand(&format!(
+                            "{} {}",
+                            path, subcommand_name
+                        ))),
+                    ),
+                }
+            }
+        }
+    }
+
+    pub fn execute(&self, cx: &Context, serenity_msg: &Message) {
+        if self.is_repeat(serenity_msg.id) {
+            return;
+        }
+        if self.ignores(cx, serenity_msg) {
+            return;
+        }
+
+        let bot_id = cx.cache.read().user.id;
+        let guild_id = serenity_msg.guild_id;
+        match self.parse(guild_id, Some(bot_id), &serenity_msg.content) {
+            Some((command, invoked, msg)) => self.run(&command, invoked, cx, serenity_msg, msg),
+            None => self.suggest_commands(cx, serenity_msg),
+        }
+    }
+
+    /// If the message looks like a mistyped command, reply with the commands it may have meant,
+    /// see `suggest_commands`. Only for messages starting with `?`, or the prefix of the guild, the
+    /// other prefixes are too likely to start a sentence
+    fn suggest_commands(&self, cx: &Context, serenity_msg: &Message) {
+        if !self.suggest_commands {
+            return;
+        }
+        let prefix = self.prefix_of(serenity_msg.guild_id);
+        let command_name = match serenity_msg.content.strip_prefix(prefix.as_str()) {
+            Some(msg) => split_command_name(msg.trim_start()).0,
+            None => return,
+        };
+        // A doubled prefix, like `??crate`, isn't meant for the bot
+        if command_name.is_empty() || command_name.starts_with(prefix.as_str()) {
+            return;
+        }
+
+        let args = Args {
+            command: command_name,
+            body: "",
+            raw_args: "",
+            prefix: &prefix,
+            params: HashMap::new(),
+            params_in_order: Vec::new(),
+            cx,
+            msg: serenity_msg,
+            http: &self.client,
+            private_reply: false,
+            reference_reply: false,
+            allow_mass_mentions: false,
+            notice: None,
+            help_footer: None,
+            shared_data: &self.shared_data,
+        };
+        // Copy the list out, so that the lock isn't held while the guards run
+        let commands = self.commands.read().clone();
+        let mut suggestions: Vec<_> = commands
+            .iter()
+            .flat_map(|command| {
+                let names = std::iter::once(command.name.as_ref());
+                names
+                    .chain(command.aliases.iter().copied())
+                    .map(move |name| (command, name))
+            })
+            .map(|(command, name)| (edit_distance(command_name, name), command, name))
+            // Allow about one typo for every three characters
+            .filter(|(distance, _, _)| distance * 3 <= command_name.chars().count())
+            // Only run the guards of the commands that are close enough
+            .filter(|(_, command, _)| self.is_visible(command, &args))
+            .map(|(distance, _, name)| (distance, format!("`{}{}`", prefix, name)))
+            .collect();
+        if suggestions.is_empty() {
+            return;
+        }
+        suggestions.sort();
+        suggestions.dedup_by(|a, b| a.1 == b.1);
+        let suggestions: Vec<_> = suggestions
+            .into_iter()
+            .take(3)
+            .map(|(_, name)| name)
+            .collect();
+
+        let reply = Locale::of(&args).text(Text::DidYouMean(command_name, &suggestions.join(", ")));
+        if let Some(reply) = self
+            .outcomes
+            .throttle_error_reply(serenity_msg.channel_id, reply)
+        {
+            if let Err(e) = crate::api::send_reply(&args, &reply) {
+                error!("{}", e);
+            }
+        }
+    }
+
+    /// Run the command a slash command interaction invokes, see `to_slash_commands`. Serenity
+    /// doesn't know interactions yet, they arrive as unknown `INTERACTION_CREATE` events.
+    ///
+    /// The interaction is acknowledged right away, and the acknowledgement is removed again once
+    /// the command is done, or right away if no command runs, so that Discord doesn't show it as
+    /// failed. Its replies are sent to the channel like those of any other command
+    pub fn execute_interaction(&self, cx: &Context, interaction: &Value) -> Result<(), Error> {
+        // Interaction and callback types
+        const APPLICATION_COMMAND: u64 = 2;
+        const DEFERRED_CHANNEL_MESSAGE: u8 = 5;
+
+        if interaction["type"].as_u64() != Some(APPLICATION_COMMAND) {
+            return Ok(());
+        }
+        let snowflake = |key: &str| interaction[key].as_str().and_then(|id| id.parse().ok());
+        let token = interaction["token"]
+            .as_str()
+            .ok_or("Interaction without token")?;
+        let id: u64 = snowflake("id").ok_or("Interaction without ID")?;
+        let application_id: u64 =
+            snowflake("application_id").ok_or("Interaction without application")?;
+        let channel_id = snowflake("channel_id").ok_or("Interaction without channel")?;
+        let data = &interaction["data"];
+        let name = data["name"].as_str().unwrap_or_default();
+
+        let callback = format!("{}/interactions/{}/{}/callback", DISCORD_API, id, token);
+        self.client
+            .post(&callback)
+            .json(&json!({ "type": DEFERRED_CHANNEL_MESSAGE }))
+            .send()?
+            .error_for_status()?;
+
+        let mut msg = CustomMessage::new();
+        msg.id(MessageId(id))
+            .channel_id(ChannelId(channel_id))
+            .content(interaction_content(data));
+        // The user is in `member` for interactions in guilds, along with their roles
+        let user = match interaction.get("member") {
+            Some(member) => {
+                msg.member(serde_json::from_value(member.clone())?);
+                &member["user"]
+            }
+            None => &interaction["user"],
+        };
+        msg.author(serde_json::from_value(user.clone())?);
+        if let Some(guild_id) = snowflake("guild_id") {
+            msg.guild_id(GuildId(guild_id));
+        }
+        let msg = msg.build();
+        match self.find_top_level(name) {
+            Some(command) if !self.ignores(cx, &msg) => {
+                self.run_interaction(&command, cx, &msg, data)
+            }
+            _ => info!("Ignoring interaction for /{}", name),
+        }
+
+        let acknowledgement = format!(
+            "{}/webhooks/{}/{}/messages/@original",
+            DISCORD_API, application_id, token
+        );
+        self.client
+            .delete(&acknowledgement)
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Execute an edited message. `old_content` is what the message said before the edit, if known
+    pub fn execute_edit(&self, cx: &Context, old_content: Option<&str>, serenity_msg: &Message) {
+        if self.ignores(cx, serenity_msg) {
+            return;
+        }
+
+        let bot_id = cx.cache.read().user.id;
+        let guild_id = serenity_msg.guild_id;
+        let (command, invoked, msg) =
+            match self.parse(guild_id, Some(bot_id), &serenity_msg.content) {
+                Some(x) => x,
+                None => return self.suggest_commands(cx, serenity_msg),
+            };
+
+        if command.skip_rerun_on_edit
+            && !self.edit_changes_command(
+                guild_id,
+                Some(bot_id),
+                old_content,
+                &serenity_msg.content,
+            )
+        {
+            return;
+        }
+
+        self.run(&command, invoked, cx, serenity_msg, msg);
+    }
+
+    /// Whether editing a message from `old_content` to `new_content` made it invoke a command it
+    /// didn't invoke before, see `Command::skip_rerun_on_edit`. The subcommands are compared too,
+    /// `?tags add x` edited into `?tags delete x` is new
+    fn edit_changes_command(
+        &self,
+        guild_id: Option<GuildId>,
+        bot_id: Option<UserId>,
+        old_content: Option<&str>,
+        new_content: &str,
+    ) -> bool {
+        let (command, invoked, msg) = match self.parse(guild_id, bot_id, new_content) {
+            Some(x) => x,
+            None => return false,
+        };
+        let path = resolve_subcommand(&command, invoked, msg).1;
+        let old_command =
+            old_content.and_then(|old_content| self.parse(guild_id, bot_id, old_content));
+        !matches!(
+            old_command,
+            Some((old_command, old_invoked, old_msg)) if Arc::ptr_eq(&old_command, &command)
+                && resolve_subcommand(&old_command, old_invoked, old_msg).1 == path
+        )
+    }
+
+    /// Whether the message was already executed within `dedup_window`, remembering it otherwise.
+    /// Edits keep the ID of the message, so `execute_edit` doesn't go through this
+    fn is_repeat(&self, message_id: MessageId) -> bool {
+        let now = Instant::now();
+        let mut recent = self.recent_messages.lock();
+
+        // Entries are in the order they were seen, so the expired ones are at the front
+        while let Some((_, &seen)) = recent.get_index(0) {
+            if now.duration_since(seen) < self.dedup_window && recent.len() < self.dedup_capacity {
+                break;
+            }
+            recent.shift_remove_index(0);
+        }
+
+        if recent.contains_key(&message_id) {
+            return true;
+        }
+        if self.dedup_capacity > 0 {
+            recent.insert(message_id, now);
+        }
+        false
+    }
+
+    /// Whether the message is never treated as a command, because the bot itself, another bot or a
+    /// banned user sent it
+    fn ignores(&self, cx: &Context, serenity_msg: &Message) -> bool {
+        let bot_id = cx.cache.read().user.id;
+        self.ignores_author(bot_id, &serenity_msg.author)
+    }
+
+    /// See `ignores`
+    fn ignores_author(&self, bot_id: UserId, author: &User) -> bool {
+        author.id == bot_id
+            || (self.ignore_bots && author.bot)
+            || self.banned_users.read().contains(&author.id)
+    }
+
+    /// Find the command the message invokes, returning it with the name it was invoked with and the
+    /// message text following that name. Without the `bot_id`, mentioning the bot isn't recognized
+    /// as a prefix.
+    ///
+    /// Whitespace between the prefix and the command name is ignored, so `? crate` is the same as
+    /// `?crate`, and a bare `?` shows the help menu. Other bare prefixes are ignored, people post a
+    /// lone 🦀 all the time. Runs of whitespace between the parameters, and whitespace around the
+    /// body, don't matter either
+    fn parse<'a>(
+        &self,
+        guild_id: Option<GuildId>,
+        bot_id: Option<UserId>,
+        content: &'a str,
+    ) -> Option<(Arc<Command>, &'a str, &'a str)> {
+        let guild_prefix = guild_id.and_then(|guild_id| self.guild_prefixes.get(guild_id));
+        let guild_prefix = guild_prefix.as_deref();
+        let (msg, bare_opens_help) = match strip_prefix(content, bot_id, guild_prefix) {
+            Some(msg) => {
+                let prefix = &content[..content.len() - msg.len()];
+                let opens_help = prefix == "?" || Some(prefix) == guild_prefix;
+                (msg.trim_start(), opens_help)
+            }
+            // The command name has to follow the prefix directly, see `find_prefix`
+            None if self.match_anywhere => (find_prefix(content, bot_id, guild_prefix)?, false),
+            None => return None,
+        };
+
+        // Find the command that matches this message
+        let (command_name, msg) = match split_command_name(msg) {
+            ("", msg) if bare_opens_help => ("help", msg),
+            x => x,
+        };
+        let command = self.find_top_level(command_name)?;
+        Some((command, command_name, msg))
+    }
+
+    /// Match a message against the registered commands without running anything, e.g. to check
+    /// how a message is parsed
+    #[cfg(test)]
+    pub(crate) fn match_command<'a>(&self, input: &'a str) -> Option<Match<'a>> {
+        let (root, invoked, msg) = self.parse(None, None, input)?;
+        let (command, command_name, _, msg) = resolve_subcommand(&root, invoked, msg);
+        let parsed = parse_message(command, msg);
+        Some(Match {
+            command_name,
+            params: parsed.params,
+            body: parsed.body,
+        })
+    }
+
+    fn find_top_level(&self, command_name: &str) -> Option<Arc<Command>> {
+        let commands = self.commands.read();
+        let index = find_command_index(&commands, command_name)?;
+        Some(Arc::clone(&commands[index]))
+    }
+
+    /// Run a top-level command with the message text following the name it was `invoked` with
+    fn run(
+        &self,
+        command: &Command,
+        invoked: &str,
+        cx: &Context,
+        serenity_msg: &Message,
+        msg: &str,
+    ) {
+        if !self.is_available(command, serenity_msg.guild_id) {
+            return;
+        }
+        let root = command.name.as_ref();
+        let (command, command_name, invoked, msg) = resolve_subcommand(command, invoked, msg);
+        let ParsedMessage {
+            params,
+            params_in_order,
+            body,
+            duplicate_param,
+            too_long,
+        } = parse_message(command, msg);
+
+        let prefix = self.prefix_of(serenity_msg.guild_id);
+        let args = Args {
+            command: &invoked,
+            body: &body,
+            raw_args: msg,
+            prefix: &prefix,
+            params,
+            params_in_order,
+            cx: &cx,
+            msg: &serenity_msg,
+            http: &self.client,
+            private_reply: command.private_reply,
+            reference_reply: command.reference_reply,
+            allow_mass_mentions: command.allow_mass_mentions,
+            notice: None,
+            help_footer: None,
+            shared_data: &self.shared_data,
+        };
+
+        self.dispatch(
+            root,
+            command,
+            &command_name,
+            &args,
+            duplicate_param,
+            too_long,
+        );
+    }
+
+    /// Run a top-level command with the options of a slash command interaction, like `run`
+    fn run_interaction(
+        &self,
+        command: &Command,
+        cx: &Context,
+        serenity_msg: &Message,
+        data: &Value,
+    ) {
+        if !self.is_available(command, serenity_msg.guild_id) {
+            return;
+        }
+        let root = command.name.as_ref();
+        let mut command = command;
+        let mut command_name = command.name.to_string();
+        let mut options = &data["options"];
+        // Subcommands are the only option of their group
+        if let CommandHandler::Group(group) = &command.handler {
+            let subcommand = options.get(0).and_then(|option| {
+                let subcommand = find_command(&group.subcommands, option["name"].as_str()?)?;
+                Some((subcommand, &option["options"]))
+            });
+            if let Some((subcommand, subcommand_options)) = subcommand {
+                command = subcommand;
+                command_name = format!("{} {}", command_name, command.name);
+                options = subcommand_options;
+            }
+        }
+        let options = text_options(options);
+        let ParsedMessage {
+            params,
+            params_in_order,
+            body,
+            duplicate_param,
+            too_long,
+        } = parse_options(command, &options);
+
+        let prefix = self.prefix_of(serenity_msg.guild_id);
+        let args = Args {
+            command: &command_name,
+            body: &body,
+            raw_args: "",
+            prefix: &prefix,
+            params,
+            params_in_order,
+            cx: &cx,
+            msg: &serenity_msg,
+            http: &self.client,
+            private_reply: command.private_reply,
+            // There's no message that could be replied to
+            reference_reply: false,
+            allow_mass_mentions: command.allow_mass_mentions,
+            notice: None,
+            help_footer: None,
+            shared_data: &self.shared_data,
+        };
+        self.dispatch(
+            root,
+            command,
+            &command_name,
+            &args,
+            duplicate_param,
+            too_long,
+        );
+    }
+
+    /// Run the resolved command with its arguments, unless something stands in the way, and
+    /// reply its error if it fails
+    fn dispatch(
+        &self,
+        root: &str,
+        command: &Command,
+        command_name: &str,
+        args: &Args,
+        duplicate_param: Option<&str>,
+        too_long: Option<&str>,
+    ) {
+        if !self.check_enabled(root, args) || !self.check_channel(root, args) {
+            return;
+        }
+
+        let invocation = Invocation {
+            root: root.to_owned(),
+            path: command_name.to_owned(),
+            name: command.name.to_string(),
+            quiet: command.quiet,
+            reply_ttl: command.reply_ttl,
+            slow_command_threshold: self.slow_command_threshold,
+        };
+        let started_at = Instant::now();
+        let command_execution_result = match check_params(command, args, duplicate_param, too_long)
+        {
+            Ok(()) => match self.run_before_hooks(args) {
+                Ok(true) => self.call(command, &invocation, args),
+                Ok(false) => return,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        // A confirmed command only runs once its author confirms it, its outcome is recorded then
+        if command.confirmation.is_some() && command_execution_result.is_ok() {
+            return;
+        }
+        self.outcomes.record(
+            &invocation,
+            args,
+            command_execution_result,
+            started_at.elapsed(),
+        );
+    }
+
+    /// Whether the command isn't disabled, see `disable`. Replies that it is, if it was disabled
+    /// that way
+    fn check_enabled(&self, root: &str, args: &Args) -> bool {
+        let reply = match self.disabled.read().get(&root.to_ascii_lowercase()) {
+            Some(&reply) => reply,
+            None => return true,
+        };
+
+        info!("Ignoring disabled command ?{}", root);
+        if reply {
+            let text = Locale::of(args).text(Text::CommandDisabled);
+            if let Err(e) = crate::api::send_reply(args, &text) {
+                error!("{}", e);
+            }
+        }
+        false
+    }
+
+    /// Whether the command may be used in the channel of the message, see `restrict_to_channels`.
+    /// Replies why not, if the restriction says so
+    fn check_channel(&self, root: &str, args: &Args) -> bool {
+        let denial = {
+            let restrictions = self.channel_restrictions.read();
+            match restrictions.get(&root.to_ascii_lowercase()) {
+                Some(restriction) if !restriction.permits(args.msg.channel_id) => restriction
+                    .reply
+                    .then(|| restriction.denial(Locale::of(args))),
+                _ => return true,
+            }
+        };
+
+        info!("Ignoring ?{} in channel {}", root, args.msg.channel_id);
+        if let Some(denial) = denial {
+            if let Err(e) = crate::api::send_reply(args, &denial) {
+                error!("{}", e);
+            }
+        }
+        false
+    }
+
+    /// Whether the command's guard lets the author of the message use it, hiding guard failures.
+    /// `path` is the full name of the command, see `check_guard`
+    fn is_allowed(&self, command: &Command, path: &str, args: &Args) -> bool {
+        self.check_guard(command, path, args).unwrap_or_else(|e| {
+            warn!("Guard of command {} failed: {}", command.name, e);
+            false
+        })
+    }
+
+    /// Evaluate the command's guard, or reuse its recent result if the command has a `guard_cache`.
+    /// Results are cached by `path`, the full name of the command including its groups
+    fn check_guard(&self, command: &Command, path: &str, args: &Args) -> Result<bool, Error> {
+        let guard = match &command.guard {
+            Some(guard) => guard,
+            None => return Ok(true),
+        };
+        let cache_duration = match command.guard_cache {
+            Some(cache_duration) => cache_duration,
+            None => return guard(args),
+        };
+
+        let key = (path.to_owned(), args.msg.author.id);
+        self.cached_guard(key, cache_duration, || guard(args))
+    }
+
+    /// Reuse the guard result cached under `key` while it is fresh, otherwise run `guard` and cache
+    /// its result for `cache_duration`
+    fn cached_guard(
+        &self,
+        key: (String, UserId),
+        cache_duration: Duration,
+        guard: impl FnOnce() -> Result<bool, Error>,
+    ) -> Result<bool, Error> {
+        if let Some(&(allowed, expires_at)) = self.guard_results.lock().get(&key) {
+            if expires_at > Instant::now() {
+                return Ok(allowed);
+            }
+        }
+
+        // Don't hold the lock while the guard runs, it may take a while
+        let allowed = guard()?;
+        let mut guard_results = self.guard_results.lock();
+        let now = Instant::now();
+        guard_results.retain(|_, (_, expires_at)| *expires_at > now);
+        guard_results.insert(key, (allowed, now + cache_duration));
+        Ok(allowed)
+    }
+
+    /// Fail if the author of the message is still on cooldown for the command whose full name is
+    /// `path`, otherwise start a new one, unless the user may bypass it
+    fn check_cooldown(
+        &self,
+        command: &Command,
+        path: &str,
+        cooldown: &Cooldown,
+        args: &Args,
+    ) -> Result<(), Error> {
+        if let Some(bypass) = &cooldown.bypass {
+            match bypass(args) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(e) => warn!("Cooldown bypass of command {} failed: {}", command.name, e),
+            }
+        }
+
+        let user_key = (path.to_owned(), args.msg.author.id);
+        let channel_key = (path.to_owned(), args.msg.channel_id);
+        let mut cooldowns = self.cooldowns.lock();
+        let mut channel_cooldowns = self.channel_cooldowns.lock();
+        let now = Instant::now();
+        // Round up, "wait 0 seconds" would be confusing
+        let seconds_left = |ends_at: Instant| (ends_at - now).as_secs() + 1;
+        if let Some(&ends_at) = cooldowns.get(&user_key).filter(|&&ends_at| ends_at > now) {
+            let reply = Locale::of(args).text(Text::OnCooldown(seconds_left(ends_at)));
+            return Err(CommandError::User(reply).into());
+        }
+        if let Some(&ends_at) = channel_cooldowns
+            .get(&channel_key)
+            .filter(|&&ends_at| ends_at > now)
+        {
+            let reply = Locale::of(args).text(Text::ChannelOnCooldown(seconds_left(ends_at)));
+            return Err(CommandError::User(reply).into());
+        }
+
+        cooldowns.retain(|_, ends_at| *ends_at > now);
+        cooldowns.insert(user_key, now + cooldown.duration);
+        channel_cooldowns.retain(|_, ends_at| *ends_at > now);
+        if let Some(channel_duration) = cooldown.channel_duration {
+            channel_cooldowns.insert(channel_key, now + channel_duration);
+        }
+        Ok(())
+    }
+
+    fn run_before_hooks(&self, args: &Args) -> Result<bool, Error> {
+        for hook in &self.before_hooks {
+            if !hook(args)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Run the command whose full name is `path`, once its cooldown and guards let it. They're
+    /// checked cheapest first, guards may have to ask Discord
+    fn call(&self, command: &Command, invocation: &Invocation, args: &Args) -> Result<(), Error> {
+        let path = invocation.path.as_str();
+        // Right command, but what it's about is missing: show how to use it instead of running it
+        if let (Body::Required(name), "") = (command.body, args.body) {
+            let mut reply = Locale::of(args).text(Text::MissingArgument(name));
+            if let Some(usage) = command.usage(args.prefix, args.command) {
+                reply = format!("{}\n{}", reply, usage);
+            }
+            return Err(CommandError::User(reply).into());
+        }
+        if let Some(cooldown) = &command.cooldown {
+            self.check_cooldown(command, path, cooldown, args)?;
+        }
+        if !self.check_guard(command, path, args)? {
+            let reply = Locale::of(args).text(Text::NoPermission);
+            return Err(CommandError::Permission(reply).into());
+        }
+        let notice = match &command.soft_guard {
+            Some(soft_guard) => match soft_guard(args)? {
+                GuardOutcome::Allow => None,
+                GuardOutcome::AllowWithNotice(notice) => Some(notice),
+                GuardOutcome::Deny(reply) => return Err(CommandError::Permission(reply).into()),
+            },
+            None => None,
+        };
+        let noticed_args;
+        let args = match &notice {
+            Some(notice) => {
+                noticed_args = Args {
+                    notice: Some(notice),
+                    ..args.clone()
+                };
+                &noticed_args
+            }
+            None => args,
+        };
+
+        if command.broadcast_typing {
+            if let Err(e) = args.msg.channel_id.broadcast_typing(&args.cx.http) {
+                warn!("Can't broadcast typing: {}", e);
+            }
+        }
+
+        match &command.handler {
+            CommandHandler::Help => self.help_menu(args),
+            CommandHandler::Custom { action, .. } => {
+                match (command.confirmation, command.timeout) {
+                    (Some(prompt), _) => ask_confirmation(
+                        action,
+                        args,
+                        prompt,
+                        Arc::clone(&self.outcomes),
+                        invocation.clone(),
+                    ),
+                    (None, _) if command.spawn => {
+                        spawn_call(&command.name, command.quiet, action, args);
+                        Ok(())
+                    }
+                    (None, Some(timeout)) => {
+                        call_with_timeout(&command.name, action, args, timeout)
+                    }
+                    (None, None) => catch_panic(&command.name, || action(args)),
+                }
+            }
+            CommandHandler::Group(group) => {
+                crate::api::send_reply(args, &group.usage(args.prefix, &command.name))
+            }
+        }
+    }
+}
+
+/// Where the outcomes of commands end up, see `Outcomes::record`. Shared with the prompts of
+/// confirmed commands, which only run once their author confirmed them
+struct Outcomes {
+    after_hooks: Vec<AfterHook>,
+    audit_sinks: Vec<Box<dyn AuditSink>>,
+    /// Shared with the metrics server, see `Commands::serve_metrics`
+    metrics: Arc<Metrics>,
+    recent_errors: Mutex<HashMap<ChannelId, RecentError>>,
+}
+
+/// The command that was run, for recording its outcome
+#[derive(Clone)]
+struct Invocation {
+    /// Name of the top-level command
+    root: String,
+    /// Full name of the command, including the groups it's in, like `config prefix`
+    path: String,
+    name: String,
+    quiet: bool,
+    reply_ttl: Option<Duration>,
+    slow_command_threshold: Duration,
+}
+
+impl Outcomes {
+    /// Record the outcome of the command in the metrics and the audit sinks, pass it to the after
+    /// hooks, and reply its error if it failed
+    fn record(
+        &self,
+        invocation: &Invocation,
+        args: &Args,
+        command_execution_result: Result<(), Error>,
+        latency: Duration,
+    ) {
+        let name = &invocation.name;
+        if latency > invocation.slow_command_threshold {
+            warn!("Executed ?{} in {}ms", name, latency.as_millis());
+        } else if !invocation.quiet {
+            info!("Executed ?{} in {}ms", name, latency.as_millis());
+        }
+        self.metrics
+            .record(&invocation.root, &command_execution_result, latency);
+        for sink in &self.audit_sinks {
+            sink.record(CommandAudit {
+                user_id: args.msg.author.id,
+                user_name: args.msg.author.tag(),
+                command: invocation.path.clone(),
+                content: args.msg.content.clone(),
+                timestamp: SystemTime::now(),
+                outcome: AuditOutcome::of(&command_execution_result),
+            });
+        }
+        for hook in &self.after_hooks {
+            hook(args, &command_execution_result);
+        }
+
+        match command_execution_result {
+            Ok(()) => {
+                if let Some(ttl) = invocation.reply_ttl {
+                    crate::api::expire_reply(args, ttl);
+                }
+            }
+            Err(e) => {
+                let reply = error_reply(name, invocation.quiet, args, &e);
+                if let Some(reply) = self.throttle_error_reply(args.msg.channel_id, reply) {
+                    if let Err(e) = crate::api::send_reply(args, &reply) {
+                        error!("{}", e)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the error reply to send to the channel, or `None` if the same error was already
+    /// sent there within the last `ERROR_REPLY_THROTTLE`. This keeps e.g. an unreachable API from
+    /// flooding the channel with identical error messages
+    fn throttle_error_reply(&self, channel_id: ChannelId, error: String) -> Option<String> {
+        let mut recent_errors = self.recent_errors.lock();
+
+        let mut suppressed = 0;
+        if let Some(recent) = recent_errors.get_mut(&channel_id) {
+            if recent.text == error {
+                if recent.sent_at.elapsed() < ERROR_REPLY_THROTTLE {
+                    recent.suppressed += 1;
+                    return None;
+                }
+                suppressed = recent.suppressed;
+            }
+        }
+
+        let reply = match suppressed {
+            0 => error.clone(),
+            n => format!("{}\n(suppressed {} repeats)", error, n),
+        };
+        recent_errors.insert(
+            channel_id,
+            RecentError {
+                text: error,
+                sent_at: Instant::now(),
+                suppressed: 0,
+            },
+        );
+        Some(reply)
+    }
+}
+
+/// What a message invokes, see `Commands::match_command`
+#[cfg(test)]
+pub(crate) struct Match<'a> {
+    /// Full name of the command, including the groups it's in, like `config set`
+    pub command_name: String,
+    pub params: HashMap<&'a str, &'a str>,
+    pub body: Cow<'a, str>,
+}
+
+/// The pages of the help menu listing `commands` under their categories, in the given order
+fn help_pages(commands: Vec<Arc<Command>>, prefix: &str) -> Vec<String> {
+    let footer = format!(
+        "\nType `{0}help command` for more info on a command, or `{0}help category` for the \
+         commands in a category.\nYou can edit your message to the bot and the bot will edit its \
+         response.",
+        prefix
+    );
+
+    let mut lines = Vec::new();
+    let mut current_category = None;
+    for command in commands {
+        if current_category != Some(command.category) {
+            lines.push(format!("**{}**", command.category.unwrap_or("Commands")));
+            current_category = Some(command.category);
+        }
+        lines.push(format!(
+            "`{}{}` {}",
+            prefix, command.name, command.inline_help
+        ));
+    }
+
+    let mut pages = vec![String::new()];
+    for line in lines {
+        let page_len = pages.last().unwrap().len();
+        if page_len > 0 && page_len + line.len() + footer.len() >= HELP_PAGE_LENGTH {
+            pages.push(String::new());
+        }
+        let page = pages.last_mut().unwrap();
+        *page += &line;
+        page.push('\n');
+    }
+    for page in &mut pages {
+        *page += &footer;
+    }
+    pages
+}
+
+/// Count the aliases of `commands`, and record the names and aliases that are taken already.
+/// Like `find_command_index`, names take precedence over aliases
+fn count_names<'a>(
+    commands: impl Iterator<Item = &'a Command> + Clone,
+    path: &str,
+    stats: &mut RegistryStats,
+) {
+    let mut taken = HashSet::new();
+    let names = commands.clone().map(|command| command.name.as_ref());
+    let aliases = commands.flat_map(|command| command.aliases.iter().copied());
+    for (name, alias) in names
+        .map(|name| (name, false))
+        .chain(aliases.map(|alias| (alias, true)))
+    {
+        stats.aliases += alias as usize;
+        if !taken.insert(name) {
+            stats.shadowed.push(format!("{}{}", path, name));
+        }
+    }
+}
+
+/// The slash command, or the option for a subcommand, for the command. See `to_slash_commands`
+fn slash_command(command: &Command, subcommand: bool) -> Option<Value> {
+    // Discord's limits for slash command names and descriptions
+    const NAME_LIMIT: usize = 32;
+    const DESCRIPTION_LIMIT: usize = 100;
+    const SUB_COMMAND: u8 = 1;
+
+    let is_slash_name = |name: &str| {
+        (1..=NAME_LIMIT).contains(&name.len())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+    };
+    let name = command.name.to_ascii_lowercase();
+    if !is_slash_name(&name) {
+        warn!(
+            "Skipping slash command for ?{}, the name isn't allowed",
+            command.name
+        );
+        return None;
+    }
+    if command.directives.is_some() {
+        warn!(
+            "Skipping slash command for ?{}, it takes code blocks",
+            command.name
+        );
+        return None;
+    }
+
+    let options: Vec<Value> = match &command.handler {
+        CommandHandler::Group(_) if subcommand => {
+            warn!(
+                "Skipping slash command for ?{}, it's a group in a group",
+                command.name
+            );
+            return None;
+        }
+        CommandHandler::Group(group) => group
+            .subcommands
+            .iter()
+            .filter_map(|subcommand| slash_command(subcommand, true))
+            .collect(),
+        _ => {
+            let body = match command.body {
+                Body::None => None,
+                Body::Optional(name) => Some((name, false)),
+                Body::Required(name) => Some((name, true)),
+            };
+            let mut arguments: Vec<_> = command
+                .positional
+                .map(|name| (name, false))
+                .into_iter()
+                .chain(command.params.iter().map(|&name| (name, false)))
+                .chain(body)
+                .collect();
+            // Discord wants the required options first
+            arguments.sort_by_key(|&(_, required)| !required);
+            arguments
+                .into_iter()
+                .map(|(name, required)| {
+                    let option_type = slash_option_type(command, name);
+                    let name = slash_option_name(name);
+                    json!({
+                        "type": option_type,
+                        "name": name,
+                        "description": name,
+                        "required": required,
+                    })
+                })
+                .collect()
+        }
+    };
+
+    let description: String = command
+        .inline_help
+        .chars()
+        .take(DESCRIPTION_LIMIT)
+        .collect();
+    let mut slash_command = json!({
+        "name": name,
+        "description": description,
+        "options": options,
+    });
+    if subcommand {
+        slash_command["type"] = SUB_COMMAND.into();
+    }
+    Some(slash_command)
+}
+
+/// Descend into the subcommand named at the start of `msg`, or else the group's default
+/// subcommand. Returns the command to run, its full name, the name it was invoked with and the
+/// rest of the message
+fn resolve_subcommand<'c, 'm>(
+    command: &'c Command,
+    invoked: &str,
+    msg: &'m str,
+) -> (&'c Command, String, String, &'m str) {
+    let mut command = command;
+    let mut command_name = command.name.to_string();
+    let mut invoked = invoked.to_owned();
+    let mut msg = msg;
+    while let CommandHandler::Group(group) = &command.handler {
+        let (subcommand_name, rest) = split_command_name(msg);
+        let subcommand = match find_command(&group.subcommands, subcommand_name) {
+            Some(subcommand) => {
+                msg = rest;
+                invoked = format!("{} {}", invoked, subcommand_name);
+                subcommand
+            }
+            None => match group
+                .default
+                .and_then(|name| find_command(&group.subcommands, name))
+            {
+                Some(default) => default,
+                // Replies with the usage of the group
+                None => break,
+            },
+        };
+        command = subcommand;
+        command_name = format!("{} {}", command_name, command.name);
+    }
+    (command, command_name, invoked, msg)
+}
+
+/// Split the key-value parameter off the start of the whitespace-separated tokens, returning the
+/// key, the value and how many tokens it spanned. With `lenient`, `key = value`, `key =value` and
+/// `key= value` are accepted along with `key=value`
+fn split_param<'a>(tokens: &[&'a str], lenient: bool) -> Option<(&'a str, &'a str, usize)> {
+    // Check that the param key is sensible, otherwise any equal sign in arg body
+    // (think ?eval) will be parsed as a parameter
+    let is_key = |key: &str| key.chars().all(|c| c.is_alphanumeric());
+
+    let token = tokens[0];
+    if let Some((key, value)) = token.split_once('=') {
+        if !is_key(key) {
+            return None;
+        }
+        return match tokens.get(1) {
+            Some(next) if lenient && value.is_empty() && !key.is_empty() => Some((key, next, 2)),
+            _ => Some((key, value, 1)),
+        };
+    }
+
+    if !lenient || !is_key(token) {
+        return None;
+    }
+    match (tokens.get(1), tokens.get(2)) {
+        (Some(&"="), Some(value)) => Some((token, value, 3)),
+        (Some(next), _) if next.len() > 1 => Some((token, next.strip_prefix('=')?, 2)),
+        _ => None,
+    }
+}
+
+/// The parameter the language tag of a code block body is passed on in, see `Command::directives`
+fn lang_param(command: &Command) -> &'static str {
+    lazy_static::lazy_static! {
+        static ref KEYS: Mutex<HashMap<&'static str, &'static str>> = Mutex::new(HashMap::new());
+    }
+
+    let name = match command.body {
+        Body::None => "code",
+        Body::Optional(name) | Body::Required(name) => name.trim_end_matches("..."),
+    };
+    // Leaked once for each body name, there are only a handful
+    KEYS.lock()
+        .entry(name)
+        .or_insert_with(|| Box::leak(format!("{}_lang", name).into_boxed_str()))
+}
+
+/// The declared parameter matching the key when ignoring case, or the key itself if there's none
+fn normalize_param_name<'a>(command: &Command, key: &'a str) -> &'a str {
+    command
+        .params
+        .iter()
+        .find(|param| param.eq_ignore_ascii_case(key))
+        .map_or(key, |param| param)
+}
+
+/// The key-value parameters and body of a message, see `parse_message`
+struct ParsedMessage<'a> {
+    params: HashMap<&'a str, &'a str>,
+    params_in_order: Vec<(&'a str, &'a str)>,
+    body: Cow<'a, str>,
+    /// The first key that was given more than once
+    duplicate_param: Option<&'a str>,
+    /// The first argument longer than `Command::max_argument_length`, unless it was truncated
+    too_long: Option<&'a str>,
+}
+
+/// Split the message text following the command name into key-value parameters and the body
+fn parse_message<'a>(command: &Command, msg: &'a str) -> ParsedMessage<'a> {
+    let mut params = HashMap::new();
+    let mut params_in_order = Vec::new();
+    let mut body = "";
+    let mut duplicate_param = None;
+    let tokens: Vec<&str> = msg.split_whitespace().collect();
+    let mut i = 0;
+    let quoted = |last: usize, value: &'a str| -> Option<(&'a str, usize)> {
+        match command.quoted_values {
+            true => quoted_value(msg, &tokens, last, value),
+            false => None,
+        }
+    };
+    if let (Some(name), Some(first)) = (command.positional, tokens.first()) {
+        if split_param(&tokens, command.lenient_params).is_none() {
+            let (value, last) = quoted(0, first).unwrap_or((first, 0));
+            params.insert(name, value);
+            params_in_order.push((name, value));
+            i = last + 1;
+        }
+    }
+    while i < tokens.len() {
+        if let Some((param_name, param_val, token_count)) =
+            split_param(&tokens[i..], command.lenient_params)
+        {
+            let (param_val, token_count) = match quoted(i + token_count - 1, param_val) {
+                Some((value, last)) => (value, last + 1 - i),
+                None => (param_val, token_count),
+            };
+            let param_name = match command.case_insensitive_params {
+                true => normalize_param_name(command, param_name),
+                false => param_name,
+            };
+            if params.insert(param_name, param_val).is_some() {
+                duplicate_param = duplicate_param.or(Some(param_name));
+            }
+            params_in_order.push((param_name, param_val));
+            i += token_count;
+            continue;
+        }
+        // If this whitespace-separated token is not a "key=value" pair, this must
+        // be the beginning of the command body. So, let's find out where we are within
+        // the msg string and set the body accordingly
+        let body_start = tokens[i].as_ptr() as usize - msg.as_ptr() as usize;
+        body = &msg[body_start..];
+        break;
+    }
+
+    let body = match command.directives {
+        Some(parse_directives) => {
+            if let Ok((Some(lang), _)) = crate::extract_code_and_lang(body) {
+                let key = lang_param(command);
+                if !params.contains_key(key) {
+                    params.insert(key, lang);
+                    params_in_order.push((key, lang));
+                }
+            }
+            let (directives, rest) = parse_directives(body);
+            for (key, value) in directives {
+                // Explicit key-value arguments take precedence
+                if !params.contains_key(key) {
+                    params.insert(key, value);
+                    params_in_order.push((key, value));
+                }
+            }
+            // Lines of directives leave a trailing newline behind
+            Cow::Owned(rest.trim().to_owned())
+        }
+        None => Cow::Borrowed(body),
+    };
+    for &(key, value) in command.defaults {
+        params.entry(key).or_insert(value);
+    }
+
+    limit_lengths(
+        command,
+        ParsedMessage {
+            params,
+            params_in_order,
+            body,
+            duplicate_param,
+            too_long: None,
+        },
+    )
+}
+
+/// If `value`, which the token `tokens[last]` ends with, opens a double-quoted value: the value
+/// inside the quotes and the index of the token that closes it. `None` if there's no closing quote
+fn quoted_value<'a>(
+    msg: &'a str,
+    tokens: &[&'a str],
+    last: usize,
+    value: &'a str,
+) -> Option<(&'a str, usize)> {
+    let offset = |part: &str| part.as_ptr() as usize - msg.as_ptr() as usize;
+    let rest = value.strip_prefix('"')?;
+    if let Some(inner) = rest.strip_suffix('"') {
+        return Some((inner, last));
+    }
+    let close = (last + 1..tokens.len()).find(|&j| tokens[j].ends_with('"'))?;
+    let end = offset(tokens[close]) + tokens[close].len() - 1;
+    Some((&msg[offset(rest)..end], close))
+}
+
+/// Truncate the arguments longer than `Command::max_argument_length`, or record the first of
+/// them in `too_long`
+fn limit_lengths<'a>(command: &Command, parsed: ParsedMessage<'a>) -> ParsedMessage<'a> {
+    let ParsedMessage {
+        mut params,
+        mut params_in_order,
+        body,
+        duplicate_param,
+        too_long: _,
+    } = parsed;
+    let max_len = command.max_argument_length;
+    let truncate = command.truncate_long_arguments;
+    let mut too_long = None;
+    for (key, value) in params.iter_mut().chain(
+        params_in_order
+            .iter_mut()
+            .map(|(key, value)| (&*key, value)),
+    ) {
+        if let Some(truncated) = truncate_to(value, max_len) {
+            match truncate {
+                true => *value = truncated,
+                false => too_long = too_long.or(Some(*key)),
+            }
+        }
+    }
+    let body = match truncate_to(&body, max_len) {
+        Some(truncated) if truncate => Cow::Owned(truncated.to_string()),
+        Some(_) => {
+            too_long = too_long.or(Some(match command.body {
+                Body::Optional(name) | Body::Required(name) => name,
+                Body::None => "body",
+            }));
+            body
+        }
+        None => body,
+    };
+
+    ParsedMessage {
+        params,
+        params_in_order,
+        body,
+        duplicate_param,
+        too_long,
+    }
+}
+
+/// The arguments given as the options of a slash command, see `slash_command`. Options the
+/// command doesn't declare are ignored
+fn parse_options<'a>(command: &Command, options: &'a Value) -> ParsedMessage<'a> {
+    let mut params = HashMap::new();
+    let mut params_in_order = Vec::new();
+    let mut body = "";
+    let body_name = match command.body {
+        Body::None => None,
+        Body::Optional(name) | Body::Required(name) => Some(name),
+    };
+    let declared = || command.positional.iter().chain(command.params);
+    for option in options.as_array().into_iter().flatten() {
+        let (name, value) = match (option["name"].as_str(), option["value"].as_str()) {
+            (Some(name), Some(value)) => (name, value),
+            _ => continue,
+        };
+        if body_name.map(slash_option_name).as_deref() == Some(name) {
+            body = value;
+        } else if let Some(&param) = declared().find(|param| slash_option_name(param) == name) {
+            params.insert(param, value);
+            params_in_order.push((param, value));
+        }
+    }
+
+    for &(key, value) in command.defaults {
+        params.entry(key).or_insert(value);
+    }
+
+    limit_lengths(
+        command,
+        ParsedMessage {
+            params,
+            params_in_order,
+            body: Cow::Borrowed(body.trim()),
+            duplicate_param: None,
+            too_long: None,
+        },
+    )
+}
+
+/// The options of an interaction with their values written like in a message, so the typed ones
+/// pass the same checks. Discord sends the IDs of users, channels and roles instead of mentions
+fn text_options(options: &Value) -> Value {
+    const USER: u64 = 6;
+    const CHANNEL: u64 = 7;
+    const ROLE: u64 = 8;
+
+    let mut options = options.clone();
+    for option in options.as_array_mut().into_iter().flatten() {
+        let value = match (option["type"].as_u64(), option.get("value")) {
+            (_, None) => continue,
+            (Some(USER), Some(Value::String(id))) => format!("<@{}>", id),
+            (Some(CHANNEL), Some(Value::String(id))) => format!("<#{}>", id),
+            (Some(ROLE), Some(Value::String(id))) => format!("<@&{}>", id),
+            (_, Some(Value::String(_))) => continue,
+            (_, Some(value)) => value.to_string(),
+        };
+        option["value"] = value.into();
+    }
+    options
+}
+
+/// The slash command of an interaction written out with its options, like
+/// `/tags add name:ferris`, to stand in for the content of a message
+fn interaction_content(data: &Value) -> String {
+    fn write_options(options: &Value, content: &mut String) {
+        for option in options.as_array().into_iter().flatten() {
+            let name = option["name"].as_str().unwrap_or_default();
+            match option.get("value") {
+                Some(Value::String(value)) => *content += &format!(" {}:{}", name, value),
+                Some(value) => *content += &format!(" {}:{}", name, value),
+                // Subcommands have options of their own instead of a value
+                None => {
+                    *content += &format!(" {}", name);
+                    write_options(&option["options"], content);
+                }
+            }
+        }
+    }
+
+    let mut content = format!("/{}", data["name"].as_str().unwrap_or_default());
+    write_options(&data["options"], &mut content);
+    content
+}
+
+/// The type of the slash command option for an argument, from its `ParamType` if it has one.
+/// Durations and untyped arguments are plain strings
+fn slash_option_type(command: &Command, name: &str) -> u8 {
+    const STRING: u8 = 3;
+    const INTEGER: u8 = 4;
+    const USER: u8 = 6;
+    const CHANNEL: u8 = 7;
+    const ROLE: u8 = 8;
+
+    let param_type = command
+        .param_types
+        .iter()
+        .find(|&&(key, _)| key == name)
+        .map(|&(_, param_type)| param_type);
+    match param_type {
+        Some(ParamType::User) => USER,
+        Some(ParamType::Channel) => CHANNEL,
+        Some(ParamType::Role) => ROLE,
+        Some(ParamType::Int) => INTEGER,
+        Some(ParamType::Duration) | None => STRING,
+    }
+}
+
+/// The name of the slash command option for an argument, see `slash_command`
+fn slash_option_name(name: &str) -> String {
+    name.trim_end_matches("...").to_ascii_lowercase()
+}
+
+/// The first `max_len` characters of `text`, or `None` if it isn't longer than that
+fn truncate_to(text: &str, max_len: usize) -> Option<&str> {
+    text.char_indices()
+        .nth(max_len)
+        .map(|(end, _)| &text[..end])
+}
+
+/// The name of the positional argument if it was given after the key-value parameters. A command
+/// without a body has no use for text after them, it's the positional argument in the wrong place
+fn misplaced_positional(
+    command: &Command,
+    params: &HashMap<&str, &str>,
+    body: &str,
+) -> Option<&'static str> {
+    let name = command.positional?;
+    let misplaced = command.body == Body::None && !body.is_empty() && !params.contains_key(name);
+    Some(name).filter(|_| misplaced)
+}
+
+/// Reject key-value parameters the command doesn't accept, before running it. Commands that
+/// declare their `params` only accept those, so that a typo in a key doesn't go unnoticed
+fn check_params(
+    command: &Command,
+    args: &Args,
+    duplicate_param: Option<&str>,
+    too_long: Option<&str>,
+) -> Result<(), Error> {
+    check_arguments(
+        command,
+        Locale::of(args),
+        &args.params,
+        &args.params_in_order,
+        args.body,
+        duplicate_param,
+        too_long,
+    )
+}
+
+/// See `check_params`, with the arguments taken apart
+fn check_arguments(
+    command: &Command,
+    locale: Locale,
+    params: &HashMap<&str, &str>,
+    params_in_order: &[(&str, &str)],
+    body: &str,
+    duplicate_param: Option<&str>,
+    too_long: Option<&str>,
+) -> Result<(), Error> {
+    if let Some(name) = too_long {
+        let reply = locale.text(Text::ArgumentTooLong(name));
+        return Err(CommandError::User(reply).into());
+    }
+
+    if let Some(param_name) = duplicate_param.filter(|_| command.reject_duplicate_params) {
+        let reply = locale.text(Text::DuplicateArgument(param_name));
+        return Err(CommandError::User(reply).into());
+    }
+
+    if !command.params.is_empty() {
+        let unknown = params_in_order
+            .iter()
+            .find(|(key, _)| !command.params.contains(key) && command.positional != Some(key));
+        if let Some((key, _)) = unknown {
+            let expected = command
+                .params
+                .iter()
+                .map(|param| format!("`{}`", param))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let reply = locale.text(Text::UnknownArgument(key, &expected));
+            return Err(CommandError::User(reply).into());
+        }
+    }
+
+    if let Some(name) = misplaced_positional(command, params, body) {
+        let reply = locale.text(Text::PositionalFirst(name));
+        return Err(CommandError::User(reply).into());
+    }
+
+    for (key, value) in params_in_order {
+        let validator = command.validators.iter().find(|(name, _)| name == key);
+        if let Some((_, pattern)) = validator {
+            let matches_whole = pattern
+                .find(value)
+                .filter(|m| m.start() == 0 && m.end() == value.len())
+                .is_some();
+            if !matches_whole {
+                let reply = locale.text(Text::InvalidArgument(key));
+                return Err(CommandError::User(reply).into());
+            }
+        }
+    }
+
+    for (key, param_type) in command.param_types {
+        if let Some(value) = params.get(key) {
+            param_type.check(key, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// A copy of `Args` that doesn't borrow anything, to run an action outside of the event handler
+struct OwnedArgs {
+    http: HttpClient,
+    cx: Context,
+    msg: Message,
+    command: String,
+    params: Vec<(String, String)>,
+    params_in_order: Vec<(String, String)>,
+    body: String,
+    raw_args: String,
+    prefix: String,
+    private_reply: bool,
+    reference_reply: bool,
+    allow_mass_mentions: bool,
+    notice: Option<String>,
+    help_footer: Option<String>,
+    shared_data: Arc<SharedData>,
+}
+
+impl OwnedArgs {
+    fn new(args: &Args) -> Self {
+        let to_owned = |key: &str, value: &str| (key.to_owned(), value.to_owned());
+        Self {
+            http: args.http.clone(),
+            cx: args.cx.clone(),
+            msg: args.msg.clone(),
+            command: args.command.to_owned(),
+            params: args.params.iter().map(|(k, v)| to_owned(k, v)).collect(),
+            params_in_order: args
+                .params_in_order
+                .iter()
+                .map(|(k, v)| to_owned(k, v))
+                .collect(),
+            body: args.body.to_owned(),
+            raw_args: args.raw_args.to_owned(),
+            prefix: args.prefix.to_owned(),
+            private_reply: args.private_reply,
+            reference_reply: args.reference_reply,
+            allow_mass_mentions: args.allow_mass_mentions,
+            notice: args.notice.map(str::to_owned),
+            help_footer: args.help_footer.map(str::to_owned),
+            shared_data: Arc::clone(args.shared_data),
+        }
+    }
+
+    fn call(&self, command_name: &str, action: &Action) -> Result<(), Error> {
+        let args = self.args();
+        catch_panic(command_name, || action(&args))
+    }
+
+    fn args(&self) -> Args<'_> {
+        Args {
+            http: &self.http,
+            cx: &self.cx,
+            msg: &self.msg,
+            command: &self.command,
+            params: self
+                .params
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect(),
+            params_in_order: self
+                .params_in_order
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect(),
+            body: &self.body,
+            raw_args: &self.raw_args,
+            prefix: &self.prefix,
+            private_reply: self.private_reply,
+            reference_reply: self.reference_reply,
+            allow_mass_mentions: self.allow_mass_mentions,
+            notice: self.notice.as_deref(),
+            help_footer: self.help_footer.as_deref(),
+            shared_data: &self.shared_data,
+        }
+    }
+}
+
+/// What to reply when a command fails. Mistakes of the user are replied as they are, anything
+/// else is logged and replied as an internal error
+fn error_reply(command_name: &str, quiet: bool, args: &Args, error: &Error) -> String {
+    match error.downcast_ref::<CommandError>() {
+        Some(CommandError::Internal(_)) | None => {
+            error!("Error when executing command {}: {}", command_name, error);
+            Locale::of(args).text(Text::InternalError)
+        }
+        Some(e) => {
+            if !quiet {
+                info!("Command {} failed: {}", command_name, e);
+            }
+            e.to_string()
+        }
+    }
+}
+
+/// Run the action of the command named `command_name`, turning a panic into an error so that it
+/// doesn't take down the thread handling the message. The panic hook already printed the panic
+/// along with its location and, if enabled, backtrace to stderr
+fn catch_panic(command_name: &str, call: impl FnOnce() -> Result<(), Error>) -> Result<(), Error> {
+    // The arguments are thrown away after the panic, and the locks guarding the shared state are
+    // released while unwinding, so nothing that outlives the action is left half-borrowed
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(call)) {
+        Ok(result) => result,
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("(no message)");
+            let panic = format!("{} panicked: {}", command_name, message);
+            Err(CommandError::Internal(panic.into()).into())
+        }
+    }
+}
+
+/// Run the action on a worker thread and stop waiting for it after `timeout`. The thread can't be
+/// stopped, but a hanging action no longer blocks the event handler
+fn call_with_timeout(
+    command_name: &str,
+    action: &Action,
+    args: &Args,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let timed_out = Locale::of(args).text(Text::CommandTimedOut);
+    let command_name = command_name.to_owned();
+    let action = Arc::clone(action);
+    let args = OwnedArgs::new(args);
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        // Nobody is listening anymore if the command timed out
+        let _ = sender.send(args.call(&command_name, &action));
+    });
+
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(_) => Err(CommandError::User(timed_out).into()),
+    }
+}
+
+/// Run the action on a thread of its own without waiting for it, see `Command::spawn`
+fn spawn_call(command_name: &str, quiet: bool, action: &Action, args: &Args) {
+    let command_name = command_name.to_owned();
+    let action = Arc::clone(action);
+    let args = OwnedArgs::new(args);
+
+    std::thread::spawn(move || {
+        let args = args.args();
+        if let Err(e) = catch_panic(&command_name, || action(&args)) {
+            let reply = error_reply(&command_name, quiet, &args, &e);
+            if let Err(e) = crate::api::send_reply(&args, &reply) {
+                error!("{}", e)
+            }
+        }
+    });
+}
+
+/// Post the prompt and only run the action once the author of the command reacts with ✅ to it,
+/// within `CONFIRMATION_TIMEOUT`. Reacting with ❌ cancels the command. Its outcome is only
+/// recorded, and its error replied, once the action ran, cancelled commands leave no record.
+///
+/// The bot needs to be able to add reactions for this. If it can't, e.g. because it lacks the
+/// permission in the channel, the command fails instead of running unconfirmed. In DMs this works
+/// as usual, except that the bot can't clear other users' reactions there.
+fn ask_confirmation(
+    action: &Action,
+    args: &Args,
+    prompt: &str,
+    outcomes: Arc<Outcomes>,
+    invocation: Invocation,
+) -> Result<(), Error> {
+    let channel_id = args.msg.channel_id;
+    let prompt = format!("{}\nReact with ✅ to confirm or ❌ to cancel.", prompt);
+    let prompt_id = crate::api::send_prompt(args, &prompt, &['✅', '❌'])?;
+    let author_id = args.msg.author.id;
+    let close_prompt = move |cx: &Context| {
+        reaction_menus::remove(cx, prompt_id);
+        // Fails if someone deleted the prompt already, which is fine
+        let _ = channel_id.delete_message(cx, prompt_id);
+    };
+
+    let cx = args.cx.clone();
+    let args = OwnedArgs::new(args);
+    let action = Arc::clone(action);
+    reaction_menus::register(&cx, prompt_id, '✅', move |cx, reaction| {
+        if reaction.user_id != author_id {
+            return Ok(());
+        }
+        close_prompt(cx);
+        let args = args.args();
+        let started_at = Instant::now();
+        let result = catch_panic(&invocation.name, || action(&args));
+        outcomes.record(&invocation, &args, result, started_at.elapsed());
+        Ok(())
+    });
+    reaction_menus::register(&cx, prompt_id, '❌', move |cx, reaction| {
+        if reaction.user_id == author_id {
+            close_prompt(cx);
+        }
+        Ok(())
+    });
+
+    std::thread::spawn(move || {
+        std::thread::sleep(CONFIRMATION_TIMEOUT);
+        close_prompt(&cx);
+    });
+    Ok(())
+}
+
+fn find_command<'a, C: Borrow<Command>>(
+    commands: &'a [C],
+    command_name: &str,
+) -> Option<&'a Command> {
+    find_command_index(commands, command_name).map(|index| commands[index].borrow())
+}
+
+/// Find the command by name or alias. A command's name takes precedence over another command's
+/// alias, regardless of which was registered first. Between several names or several aliases,
+/// the first registered command wins
+fn find_command_index<C: Borrow<Command>>(commands: &[C], command_name: &str) -> Option<usize> {
+    let by_name = commands
+        .iter()
+        .position(|cmd| cmd.borrow().name.eq_ignore_ascii_case(command_name));
+    by_name.or_else(|| {
+        commands.iter().position(|cmd| {
+            cmd.borrow()
+                .aliases
+                .iter()
+                .any(|alias| alias.eq_ignore_ascii_case(command_name))
+        })
+    })
+}
+
+/// The number of characters to insert, remove or replace to turn `a` into `b`, ignoring case
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().flat_map(char::to_lowercase).collect();
+    let b: Vec<char> = b.chars().flat_map(char::to_lowercase).collect();
+    // Distances from the prefixes of `a` to the prefix of `b` handled so far
+    let mut distances: Vec<usize> = (0..=a.len()).collect();
+    for (j, b_char) in b.iter().enumerate() {
+        let mut diagonal = distances[0];
+        distances[0] = j + 1;
+        for (i, a_char) in a.iter().enumerate() {
+            let replaced = diagonal + (a_char != b_char) as usize;
+            diagonal = distances[i + 1];
+            distances[i + 1] = replaced.min(distances[i] + 1).min(diagonal + 1);
+        }
+    }
+    distances[a.len()]
+}
+
+/// Strip the first matching prefix off the message. Mentioning the bot, like `@ferris help`, works
+/// as a prefix too. The prefix of the guild works along with `?`, see `Commands::guild_prefixes`.
+///
+/// The mention and the other built-in prefixes are tried before the guild prefix, so that a prefix
+/// like `h` doesn't cut `hey ferris` short. A guild prefix ending in a letter or digit only counts
+/// if the command name follows it directly, otherwise every `h word` would be taken for a command
+fn strip_prefix<'a>(
+    content: &'a str,
+    bot_id: Option<UserId>,
+    guild_prefix: Option<&str>,
+) -> Option<&'a str> {
+    if let Some(bot_id) = bot_id {
+        let mentions = [format!("<@{}>", bot_id.0), format!("<@!{}>", bot_id.0)];
+        if let Some(msg) = mentions
+            .iter()
+            .find_map(|mention| content.strip_prefix(mention.as_str()))
+        {
+            return Some(msg.trim_start());
+        }
+    }
+
+    let mut spoken = PREFIXES.iter().filter(|&&prefix| prefix != "?");
+    if let Some(msg) = spoken.find_map(|prefix| content.strip_prefix(prefix)) {
+        return Some(msg);
+    }
+
+    let guild_msg = guild_prefix.and_then(|prefix| {
+        let msg = content.strip_prefix(prefix)?;
+        let spelled_out = prefix.ends_with(char::is_alphanumeric);
+        let name_follows = msg.starts_with(|c: char| !c.is_whitespace());
+        Some(msg).filter(|_| name_follows || !spelled_out)
+    });
+    guild_msg.or_else(|| content.strip_prefix('?'))
+}
+
+/// Like `strip_prefix`, but for the first prefix anywhere in `content` that starts a word, so that
+/// `What? help me` or an escaped `\?crate` isn't taken for a command
+fn find_prefix<'a>(
+    content: &'a str,
+    bot_id: Option<UserId>,
+    guild_prefix: Option<&str>,
+) -> Option<&'a str> {
+    content
+        .char_indices()
+        .filter(|&(start, _)| match content[..start].chars().next_back() {
+            Some(before) => before.is_whitespace(),
+            None => true,
+        })
+        .find_map(|(start, _)| strip_prefix(&content[start..], bot_id, guild_prefix))
+}
+
+/// Split the command name off the start of the message, returning the name and the trimmed rest.
+/// Any run of whitespace ends the name, including tabs and newlines. This is used for commands,
+/// subcommands and `?help command` alike, so that `?help` finds a command however it's invoked
+fn split_command_name(msg: &str) -> (&str, &str) {
+    let (command_name, rest) =
+        msg.split_at(msg.find(char::is_whitespace).unwrap_or_else(|| msg.len()));
+    (command_name, rest.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noop(_: &Args) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn commands() -> Commands {
+        let mut cmds = Commands::new();
+        let crate_ = cmds.add("crate", noop, "", noop);
+        crate_.aliases = &["crates"];
+        crate_.body = Body::Required("query");
+        let tags = cmds.group("tags", "");
+        tags.add("add", noop, "", noop).params = &["name"];
+        tags.add("delete", noop, "", noop);
+        cmds
+    }
+
+    #[test]
+    fn matches_names_and_aliases() {
+        let cmds = commands();
+        assert_eq!(
+            cmds.match_command("?crate serde").unwrap().command_name,
+            "crate"
+        );
+        assert_eq!(
+            cmds.match_command("?crates serde").unwrap().command_name,
+            "crate"
+        );
+        assert_eq!(
+            cmds.match_command("?CRATE serde").unwrap().command_name,
+            "crate"
+        );
+        assert!(cmds.match_command("?cratez serde").is_none());
+        assert!(cmds.match_command("crate serde").is_none());
+    }
+
+    #[test]
+    fn matches_subcommands() {
+        let cmds = commands();
+        let matched = cmds.match_command("?tags delete ferris").unwrap();
+        assert_eq!(matched.command_name, "tags delete");
+        assert_eq!(matched.body, "ferris");
+    }
+
+    #[test]
+    fn matches_params_and_body() {
+        let cmds = commands();
+        let matched = cmds
+            .match_command("?tags add name=ferris  the crab ")
+            .unwrap();
+        assert_eq!(matched.command_name, "tags add");
+        assert_eq!(matched.params.get("name"), Some(&"ferris"));
+        assert_eq!(matched.body, "the crab");
+    }
+
+    #[test]
+    fn bare_group_runs_its_default() {
+        let mut cmds = Commands::new();
+        let config = cmds.group("config", "");
+        config.add("show", noop, "", noop);
+        config.add("set", noop, "", noop);
+        config.default = Some("show");
+
+        assert_eq!(
+            cmds.match_command("?config").unwrap().command_name,
+            "config show"
+        );
+        assert_eq!(
+            cmds.match_command("?config set").unwrap().command_name,
+            "config set"
+        );
+        // Anything that isn't a subcommand is left to the default
+        let matched = cmds.match_command("?config verbose").unwrap();
+        assert_eq!(matched.command_name, "config show");
+        assert_eq!(matched.body, "verbose");
+    }
+
+    #[test]
+    fn error_throttle_suppresses_repeats() {
+        let outcomes = &Commands::new().outcomes;
+        let throttle = |channel_id, error: &str| {
+            outcomes.throttle_error_reply(ChannelId(channel_id), error.to_owned())
+        };
+        assert_eq!(throttle(1, "down").as_deref(), Some("down"));
+        assert_eq!(throttle(1, "down"), None);
+        assert_eq!(throttle(1, "down"), None);
+        assert_eq!(throttle(2, "down").as_deref(), Some("down"));
+
+        // Once the throttle passed, the reply says how many were left out
+        let sent_at = Instant::now().checked_sub(ERROR_REPLY_THROTTLE).unwrap();
+        outcomes
+            .recent_errors
+            .lock()
+            .get_mut(&ChannelId(1))
+            .unwrap()
+            .sent_at = sent_at;
+        assert_eq!(
+            throttle(1, "down").as_deref(),
+            Some("down\n(suppressed 2 repeats)")
+        );
+        assert_eq!(throttle(1, "other").as_deref(), Some("other"));
+    }
+
+    fn mode_directive(body: &str) -> (Vec<(&str, &str)>, String) {
+        comment_directives(body, &["mode"])
+    }
+
+    #[test]
+    fn directives_are_taken_from_code_blocks() {
+        let (directives, body) =
+            comment_directives("// mode: release\n// a comment\nfoo", &["mode"]);
+        assert_eq!(directives, [("mode", "release")]);
+        assert_eq!(body, "// a comment\nfoo\n");
+
+        let mut cmds = Commands::new();
+        cmds.add("play", noop, "", noop).directives = Some(mode_directive);
+        let matched = cmds
+            .match_command("?play ```rust\n// mode: release\nfn main() {}\n```")
+            .unwrap();
+        assert_eq!(matched.params.get("mode"), Some(&"release"));
+        assert_eq!(matched.body, "```rust\nfn main() {}\n```");
+
+        // Explicit arguments take precedence
+        let matched = cmds
+            .match_command("?play mode=debug ```rust\n// mode: release\nfn main() {}\n```")
+            .unwrap();
+        assert_eq!(matched.params.get("mode"), Some(&"debug"));
+        let play = cmds.find_top_level("play").unwrap();
+        let parsed = parse_message(&play, "mode=debug ```rust\n// mode: release\n```");
+        assert_eq!(
+            parsed.params_in_order,
+            [("mode", "debug"), ("code_lang", "rust")]
+        );
+    }
+
+    #[test]
+    fn restore_rolls_back_to_the_snapshot() {
+        let cmds = commands();
+        let snapshot = cmds.snapshot();
+        assert!(cmds.unregister("crate"));
+        cmds.register("ferris".to_owned(), noop, "", noop).unwrap();
+        assert!(cmds.match_command("?crate serde").is_none());
+
+        cmds.restore(&snapshot);
+        assert_eq!(
+            cmds.match_command("?crate serde").unwrap().command_name,
+            "crate"
+        );
+        assert!(cmds.match_command("?ferris").is_none());
+    }
+
+    fn guild(id: u64, member_count: u64) -> Guild {
+        serde_json::from_value(json!({
+            "id": id.to_string(),
+            "name": "",
+            "owner_id": "1",
+            "region": "",
+            "afk_timeout": 0,
+            "channels": [],
+            "default_message_notifications": 0,
+            "emojis": [],
+            "explicit_content_filter": 0,
+            "features": [],
+            "joined_at": "2020-01-01T00:00:00+00:00",
+            "large": false,
+            "member_count": member_count,
+            "members": [],
+            "mfa_level": 0,
+            "presences": [],
+            "roles": [],
+            "verification_level": 0,
+            "voice_states": [],
+            "preferred_locale": "en-US",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn counts_shards_guilds_and_users() {
+        let mut cache = Cache::default();
+        assert_eq!(cached_shard_count(&cache), 1);
+        assert_eq!(cached_guild_count(&cache), 0);
+        assert_eq!(cached_user_count(&cache), 0);
+
+        cache.shard_count = 4;
+        for (id, member_count) in [(10, 100), (11, 50)].iter().copied() {
+            let guild = Arc::new(RwLock::new(guild(id, member_count)));
+            cache.guilds.insert(GuildId(id), guild);
+        }
+        cache.unavailable_guilds.insert(GuildId(12));
+        assert_eq!(cached_shard_count(&cache), 4);
+        assert_eq!(cached_guild_count(&cache), 3);
+        assert_eq!(cached_user_count(&cache), 150);
+
+        // Before the member counts are in, the cached users are all there is
+        cache.guilds.clear();
+        for id in 0..3 {
+            let user = user(id, false);
+            cache.users.insert(UserId(id), Arc::new(RwLock::new(user)));
+        }
+        assert_eq!(cached_user_count(&cache), 3);
+    }
+
+    #[test]
+    fn whitespace_after_the_prefix_is_ignored() {
+        let cmds = commands();
+        let parse = |content| {
+            let (command, invoked, msg) = cmds.parse(None, None, content)?;
+            Some((command.name.to_string(), invoked, msg))
+        };
+        assert_eq!(
+            parse("? crate serde"),
+            Some(("crate".to_owned(), "crate", "serde"))
+        );
+        assert_eq!(
+            parse("?crate   serde "),
+            Some(("crate".to_owned(), "crate", "serde"))
+        );
+        // A bare `?` opens the help menu, other bare prefixes are left alone
+        assert_eq!(parse("?"), Some(("help".to_owned(), "help", "")));
+        assert_eq!(parse("?  "), Some(("help".to_owned(), "help", "")));
+        assert_eq!(parse("🦀"), None);
+        assert_eq!(parse("🦀 "), None);
+        assert_eq!(parse("hey ferris can you please "), None);
+    }
+
+    #[test]
+    fn lenient_params_allow_spaces_around_the_equals() {
+        let split = |msg: &'static str, lenient| {
+            let tokens: Vec<_> = msg.split_whitespace().collect();
+            split_param(&tokens, lenient)
+        };
+        assert_eq!(split("key=v rest", false), Some(("key", "v", 1)));
+        assert_eq!(split("key = v rest", true), Some(("key", "v", 3)));
+        assert_eq!(split("key =v rest", true), Some(("key", "v", 2)));
+        assert_eq!(split("key= v rest", true), Some(("key", "v", 2)));
+        assert_eq!(split("key = v rest", false), None);
+        assert_eq!(split("key= v rest", false), Some(("key", "", 1)));
+        // A lone `=` doesn't name a key, so it doesn't take the next token as its value
+        assert_eq!(split("= v", true), Some(("", "", 1)));
+        assert_eq!(split("key =", true), None);
+    }
+
+    fn user(id: u64, bot: bool) -> User {
+        serde_json::from_value(json!({
+            "id": id.to_string(),
+            "username": "",
+            "discriminator": "0001",
+            "avatar": null,
+            "bot": bot,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn ignores_itself_bots_and_banned_users() {
+        let mut cmds = commands();
+        let bot_id = UserId(1);
+        assert!(cmds.ignores_author(bot_id, &user(1, true)));
+        assert!(cmds.ignores_author(bot_id, &user(2, true)));
+        assert!(!cmds.ignores_author(bot_id, &user(3, false)));
+
+        cmds.ignore_bots = false;
+        assert!(cmds.ignores_author(bot_id, &user(1, true)));
+        assert!(!cmds.ignores_author(bot_id, &user(2, true)));
+
+        cmds.set_banned_users(std::iter::once(UserId(3)).collect());
+        assert!(cmds.ignores_author(bot_id, &user(3, false)));
+    }
+
+    #[test]
+    fn names_take_precedence_over_aliases() {
+        let command = |name, aliases| {
+            let mut command = Command::new(name, "", CommandHandler::Help);
+            command.aliases = aliases;
+            command
+        };
+        let commands = [
+            command("docs", &["doc", "d"]),
+            command("doc", &[]),
+            command("dice", &["d"]),
+        ];
+        assert_eq!(find_command_index(&commands, "doc"), Some(1));
+        assert_eq!(find_command_index(&commands, "DOCS"), Some(0));
+        // Between aliases, the first registered command wins
+        assert_eq!(find_command_index(&commands, "d"), Some(0));
+        assert_eq!(find_command_index(&commands, "roll"), None);
+    }
+
+    #[test]
+    fn positional_argument_comes_first() {
+        let mut cmds = Commands::new();
+        let remind = cmds.add("remind", noop, "", noop);
+        remind.positional = Some("time");
+        remind.params = &["text"];
+        let remind = cmds.find_top_level("remind").unwrap();
+
+        let parsed = parse_message(&remind, "10m text=tea");
+        assert_eq!(parsed.params_in_order, [("time", "10m"), ("text", "tea")]);
+        assert_eq!(
+            misplaced_positional(&remind, &parsed.params, &parsed.body),
+            None
+        );
+
+        // It's optional
+        let parsed = parse_message(&remind, "text=tea");
+        assert_eq!(parsed.params_in_order, [("text", "tea")]);
+        assert_eq!(
+            misplaced_positional(&remind, &parsed.params, &parsed.body),
+            None
+        );
+
+        let parsed = parse_message(&remind, "text=tea 10m");
+        assert_eq!(parsed.body, "10m");
+        assert_eq!(
+            misplaced_positional(&remind, &parsed.params, &parsed.body),
+            Some("time")
+        );
+    }
+
+    #[test]
+    fn help_splits_names_on_any_whitespace() {
+        let cmds = commands();
+        for input in &["?help tags add", "?help  tags\tadd ", "?help\ntags\n\nadd"] {
+            let matched = cmds.match_command(input).unwrap();
+            assert_eq!(matched.command_name, "help");
+            // Like `help_menu` and `command_help` resolve it
+            let (name, rest) = split_command_name(&matched.body);
+            assert_eq!(cmds.find_top_level(name).unwrap().name, "tags");
+            assert_eq!(split_command_name(rest), ("add", ""));
+        }
+    }
+
+    #[test]
+    fn mentions_in_key_value_args() {
+        let mut cmds = Commands::new();
+        cmds.add("ban", noop, "", noop).params = &["user", "role", "channel"];
+        let matched = cmds
+            .match_command("?ban user=<@!123> role=<@&45> channel=<#67>")
+            .unwrap();
+        let param = |key| matched.params[key];
+        assert_eq!(parse_user("user", param("user")).unwrap(), UserId(123));
+        assert_eq!(parse_role("role", param("role")).unwrap(), RoleId(45));
+        assert_eq!(
+            parse_channel("channel", param("channel")).unwrap(),
+            ChannelId(67)
+        );
+        assert_eq!(parse_user("user", "<@123>").unwrap(), UserId(123));
+
+        // A role mention isn't a user mention, and nor is a bare ID
+        let error = parse_user("user", param("role")).unwrap_err();
+        assert_eq!(error.to_string(), "argument `user` must be a user mention");
+        assert!(parse_user("user", "123").is_err());
+        assert!(parse_channel("channel", "<#67").is_err());
+    }
+
+    #[test]
+    fn arguments_are_normalized_by_segment() {
+        let mut cmds = Commands::new();
+        let remind = cmds.add("remind", noop, "", noop);
+        remind.positional = Some("time");
+        remind.quoted_values = true;
+        remind.body = Body::Optional("text");
+        cmds.add("play", noop, "", noop).directives = Some(mode_directive);
+
+        // The message is split on any whitespace, so values never end in a newline
+        let matched = cmds
+            .match_command("?remind 10m\nrepeat=2\n  tea  ")
+            .unwrap();
+        assert_eq!(matched.params.get("time"), Some(&"10m"));
+        assert_eq!(matched.params.get("repeat"), Some(&"2"));
+        // Quoted values keep their whitespace, inside the quotes only
+        let matched = cmds
+            .match_command("?remind \" 10 m\" note=\"a\tb\"\n")
+            .unwrap();
+        assert_eq!(matched.params.get("time"), Some(&" 10 m"));
+        assert_eq!(matched.params.get("note"), Some(&"a\tb"));
+        // The body is trimmed, whitespace inside it is kept
+        let matched = cmds
+            .match_command("?remind 10m \n  tea\n    now \n")
+            .unwrap();
+        assert_eq!(matched.body, "tea\n    now");
+        // Also when directives were taken out of it
+        let matched = cmds
+            .match_command("?play\n// mode: release\n  fn main() {}\n")
+            .unwrap();
+        assert_eq!(matched.body, "fn main() {}");
+    }
+
+    #[test]
+    fn prefixes_in_the_middle_start_a_word() {
+        let mut cmds = commands();
+        cmds.match_anywhere = true;
+        let name = |content| Some(cmds.parse(None, None, content)?.0.name.to_string());
+        assert_eq!(name("hey bot, ?crate serde").as_deref(), Some("crate"));
+        // Sentences ending in `?`, or with a `?` followed by a space, are no commands
+        assert_eq!(name("What? crate me"), None);
+        assert_eq!(name("does ?help work?"), Some("help".to_owned()));
+        assert_eq!(name("is there a crate for this?"), None);
+        assert_eq!(name("why ? crate serde"), None);
+        // Neither are escaped or doubled prefixes
+        assert_eq!(name("type \\?crate serde"), None);
+        assert_eq!(name("type ??crate serde"), None);
+    }
+
+    #[test]
+    fn caches_guard_results_within_the_window() {
+        use std::cell::Cell;
+
+        let commands = commands();
+        let runs = Cell::new(0);
+        let guard = || {
+            runs.set(runs.get() + 1);
+            Ok(true)
+        };
+        let key = |path: &str| (path.to_owned(), UserId(1));
+        let window = Duration::from_secs(60);
+
+        assert!(commands.cached_guard(key("ban"), window, guard).unwrap());
+        assert!(commands.cached_guard(key("ban"), window, guard).unwrap());
+        assert_eq!(runs.get(), 1);
+        // Cached per command
+        assert!(commands.cached_guard(key("kick"), window, guard).unwrap());
+        assert_eq!(runs.get(), 2);
+        // Expired results are evaluated again
+        commands
+            .cached_guard(key("mute"), Duration::from_secs(0), guard)
+            .unwrap();
+        commands
+            .cached_guard(key("mute"), Duration::from_secs(0), guard)
+            .unwrap();
+        assert_eq!(runs.get(), 4);
+    }
+
+    #[test]
+    fn retries_incomplete_downloads_once() {
+        let mut downloads = 0;
+        let flaky = download_complete("main.rs", 4, || {
+            downloads += 1;
+            Ok(vec![0; 2 * downloads])
+        });
+        assert_eq!(flaky.unwrap().len(), 4);
+        assert_eq!(downloads, 2);
+
+        let truncated = download_complete("main.rs", 4, || Ok(vec![0; 3])).unwrap_err();
+        assert_eq!(
+            truncated.to_string(),
+            "download of `main.rs` was incomplete (3 of 4 bytes)"
+        );
+    }
+
+    #[test]
+    fn reruns_edits_that_change_the_command() {
+        let cmds = commands();
+        let changes = |old, new| cmds.edit_changes_command(None, None, old, new);
+
+        // The edit added the command, or the old message isn't cached
+        assert!(changes(Some("what's the crate for serde?"), "?crate serde"));
+        assert!(changes(None, "?crate serde"));
+        // The command word changed
+        assert!(changes(Some("?tags add ferris"), "?crate ferris"));
+        assert!(changes(Some("?tags add ferris"), "?tags delete ferris"));
+
+        // Only the text around the command changed
+        assert!(!changes(Some("?crate serde"), "?crate serde_json"));
+        assert!(!changes(Some("?crate serde"), "?crates serde"));
+        assert!(!changes(
+            Some("?tags add ferris"),
+            "?tags add ferris the crab"
+        ));
+        assert!(!changes(Some("?crate serde"), "no command anymore"));
+    }
+
+    #[test]
+    fn types_slash_command_options() {
+        let mut cmds = Commands::new();
+        let moderation = cmds.group("mod", "Moderation");
+        let mute = moderation.add("mute", noop, "Mutes a member", noop);
+        mute.positional = Some("member");
+        mute.params = &["minutes", "in"];
+        mute.param_types = &[
+            ("member", ParamType::User),
+            ("minutes", ParamType::Int),
+            ("in", ParamType::Channel),
+        ];
+        mute.body = Body::Required("reason");
+
+        assert_eq!(
+            cmds.to_slash_commands(),
+            vec![json!({
+                "name": "mod",
+                "description": "Moderation",
+                "options": [{
+                    "type": 1,
+                    "name": "mute",
+                    "description": "Mutes a member",
+                    "options": [
+                        { "type": 3, "name": "reason", "description": "reason", "required": true },
+                        { "type": 6, "name": "member", "description": "member", "required": false },
+                        { "type": 4, "name": "minutes", "description": "minutes", "required": false },
+                        { "type": 7, "name": "in", "description": "in", "required": false },
+                    ],
+                }],
+            })]
+        );
+    }
+
+    #[test]
+    fn writes_typed_interaction_options_like_messages() {
+        let options = json!([
+            { "type": 6, "name": "member", "value": "80351110224678912" },
+            { "type": 7, "name": "in", "value": "381880193251409931" },
+            { "type": 8, "name": "role", "value": "41771983423143936" },
+            { "type": 4, "name": "minutes", "value": 10 },
+            { "type": 3, "name": "reason", "value": "spam" },
+        ]);
+        let values: Vec<_> = text_options(&options)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|option| option["value"].as_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(
+            values,
+            [
+                "<@80351110224678912>",
+                "<#381880193251409931>",
+                "<@&41771983423143936>",
+                "10",
+                "spam"
+            ]
+        );
+        assert!(ParamType::User.check("member", &values[0]).is_ok());
+        assert!(ParamType::Int.check("minutes", &values[3]).is_ok());
+    }
+
+    #[test]
+    fn checks_param_types() {
+        let accepts = |param_type: ParamType, value| param_type.check("x", value).is_ok();
+
+        assert!(accepts(ParamType::User, "<@80351110224678912>"));
+        assert!(accepts(ParamType::User, "<@!80351110224678912>"));
+        assert!(!accepts(ParamType::User, "<#80351110224678912>"));
+        assert!(!accepts(ParamType::User, "@ferris"));
+
+        assert!(accepts(ParamType::Channel, "<#381880193251409931>"));
+        assert!(!accepts(ParamType::Channel, "<@381880193251409931>"));
+        assert!(!accepts(ParamType::Channel, "#general"));
+
+        assert!(accepts(ParamType::Role, "<@&41771983423143936>"));
+        assert!(!accepts(ParamType::Role, "<@41771983423143936>"));
+        assert!(!accepts(ParamType::Role, "@moderators"));
+
+        assert!(accepts(ParamType::Int, "10"));
+        assert!(accepts(ParamType::Int, "-3"));
+        assert!(!accepts(ParamType::Int, "ten"));
+        assert!(!accepts(ParamType::Int, "1.5"));
+
+        assert!(accepts(ParamType::Duration, "1h30m"));
+        assert!(!accepts(ParamType::Duration, "soon"));
+        assert!(!accepts(ParamType::Duration, "-1h"));
+    }
+
+    #[test]
+    fn passes_the_language_of_code_blocks_on() {
+        let mut cmds = Commands::new();
+        cmds.add("play", noop, "", noop).directives = Some(mode_directive);
+        let eval = cmds.add("eval", noop, "", noop);
+        eval.directives = Some(mode_directive);
+        eval.body = Body::Required("expr...");
+
+        let matched = cmds
+            .match_command("?play ```py\n// mode: release\nprint()\n```")
+            .unwrap();
+        assert_eq!(matched.params.get("code_lang"), Some(&"py"));
+        assert_eq!(matched.params.get("mode"), Some(&"release"));
+        let matched = cmds.match_command("?eval ```rust\n1 + 1\n```").unwrap();
+        assert_eq!(matched.params.get("expr_lang"), Some(&"rust"));
+
+        // Untagged blocks have no language
+        let matched = cmds.match_command("?play ```\nprint()\n```").unwrap();
+        assert_eq!(matched.params.get("code_lang"), None);
+        let matched = cmds.match_command("?play `print()`").unwrap();
+        assert_eq!(matched.params.get("code_lang"), None);
+    }
+
+    #[test]
+    fn describes_the_registered_commands() {
+        let cmds = commands();
+        cmds.disable("tags", false);
+
+        let manifest = cmds.manifest();
+        let names: Vec<_> = manifest.iter().map(|info| info.name.as_str()).collect();
+        assert_eq!(names, ["help", "crate", "tags"]);
+        assert_eq!(manifest[1].aliases, ["crates"]);
+        assert!(manifest[1].enabled);
+        let subcommands: Vec<_> = manifest[2]
+            .subcommands
+            .iter()
+            .map(|info| (info.name.as_str(), info.enabled))
+            .collect();
+        assert_eq!(subcommands, [("add", false), ("delete", false)]);
+
+        assert_eq!(
+            manifest[1].usage.as_deref(),
+            Some("Usage: `?crate <query>`")
+        );
+        assert_eq!(
+            manifest[2].subcommands[0].usage.as_deref(),
+            Some("Usage: `?tags add [name=…]`")
+        );
+
+        assert_eq!(
+            cmds.describe(),
+            "?help [uncategorized]
+?crate (aliases: crates) [uncategorized]
+?tags [uncategorized], disabled
+?tags add [uncategorized], disabled
+?tags delete [uncategorized], disabled
+"
+        );
+    }
+
+    #[test]
+    fn parses_durations() {
+        let secs = |secs| Ok(Duration::from_secs(secs));
+        let cases: &[(&str, Result<Duration, ()>)] = &[
+            ("90s", secs(90)),
+            ("15m", secs(15 * 60)),
+            ("2h", secs(2 * 60 * 60)),
+            ("1d", secs(24 * 60 * 60)),
+            ("1h30m", secs(90 * 60)),
+            ("1d2h3m4s", secs(24 * 60 * 60 + 2 * 60 * 60 + 3 * 60 + 4)),
+            ("30m1h", secs(90 * 60)),
+            ("0s", secs(0)),
+            ("0h0m", secs(0)),
+            ("18446744073709551615s", secs(u64::MAX)),
+            ("18446744073709551615m", Err(())),
+            ("18446744073709551615s1s", Err(())),
+            ("99999999999999999999s", Err(())),
+            ("", Err(())),
+            ("-5m", Err(())),
+            ("10", Err(())),
+            ("m", Err(())),
+            ("5w", Err(())),
+            ("5 m", Err(())),
+            ("1h30", Err(())),
+            ("soon", Err(())),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(&parse_duration(text).map_err(drop), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn splits_param_lists() {
+        assert_eq!(split_list(Some("mod,helper,vip")), ["mod", "helper", "vip"]);
+        assert_eq!(split_list(Some("mod,,helper,")), ["mod", "helper"]);
+        assert_eq!(split_list(Some(" mod , helper ")), ["mod", "helper"]);
+        assert_eq!(split_list(Some(", ,")), Vec::<&str>::new());
+        assert_eq!(split_list(Some("")), Vec::<&str>::new());
+        assert_eq!(split_list(None), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn rejects_params_failing_their_validator() {
+        let mut cmds = Commands::new();
+        let validators = [("limit", Regex::new(r"\d+").unwrap())];
+        cmds.add_validated("cleanup", noop, "", noop, &validators);
+        let cleanup = cmds.find_top_level("cleanup").unwrap();
+        let check = |msg| {
+            let parsed = parse_message(&cleanup, msg);
+            check_arguments(
+                &cleanup,
+                Locale::English,
+                &parsed.params,
+                &parsed.params_in_order,
+                &parsed.body,
+                parsed.duplicate_param,
+                parsed.too_long,
+            )
+        };
+
+        assert!(check("limit=10").is_ok());
+        // The whole value has to match, not just a part of it
+        for msg in &["limit=ten", "limit=10x", "limit="] {
+            let error = check(msg).unwrap_err();
+            assert!(matches!(error.downcast_ref(), Some(CommandError::User(_))));
+            assert_eq!(error.to_string(), "argument `limit` is invalid");
+        }
+    }
+
+    #[test]
+    fn parses_numbers_in_range() {
+        assert_eq!(parse_in_range("limit", "1", 1, 100).unwrap(), 1);
+        assert_eq!(parse_in_range("limit", "100", 1, 100).unwrap(), 100);
+        assert_eq!(parse_in_range("limit", "42", 1, 100).unwrap(), 42);
+
+        let out_of_range = "argument `limit` must be between 1 and 100";
+        for value in &["0", "101", "-5"] {
+            let error = parse_in_range("limit", value, 1, 100).unwrap_err();
+            assert_eq!(error.to_string(), out_of_range);
+        }
+
+        let not_a_number = "argument `limit` must be a number";
+        for value in &["", "ten", "1.5", "10 "] {
+            let error = parse_in_range("limit", value, 1, 100).unwrap_err();
+            assert_eq!(error.to_string(), not_a_number);
+        }
+
+        let error = parse_in_range("ratio", "NaN", 0.0, 1.0).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "argument `ratio` must be between 0 and 1"
+        );
+    }
+
+    #[test]
+    fn matches_param_keys_ignoring_case() {
+        let mut cmds = Commands::new();
+        let cleanup = cmds.add("cleanup", noop, "", noop);
+        cleanup.params = &["limit", "user"];
+        cleanup.case_insensitive_params = true;
+        cmds.add("ban", noop, "", noop).params = &["user"];
+        let cleanup = cmds.find_top_level("cleanup").unwrap();
+        let ban = cmds.find_top_level("ban").unwrap();
+
+        let lower = parse_message(&cleanup, "limit=10 user=Ferris");
+        let mixed = parse_message(&cleanup, "Limit=10 USER=Ferris");
+        assert_eq!(mixed.params, lower.params);
+        assert_eq!(mixed.params_in_order, [("limit", "10"), ("user", "Ferris")]);
+
+        // Keys that differ only in case are the same parameter given twice
+        let twice = parse_message(&cleanup, "limit=1 LIMIT=2");
+        assert_eq!(twice.duplicate_param, Some("limit"));
+        assert_eq!(twice.params["limit"], "2");
+
+        // Commands that don't opt in keep the key as it was typed
+        let ban = parse_message(&ban, "User=Ferris");
+        assert_eq!(ban.params_in_order, [("User", "Ferris")]);
+    }
+
+    #[test]
+    fn skips_repeated_messages_within_the_window() {
+        let cmds = Commands::new();
+        assert!(!cmds.is_repeat(MessageId(1)));
+        assert!(cmds.is_repeat(MessageId(1)));
+        assert!(!cmds.is_repeat(MessageId(2)));
+        assert!(cmds.is_repeat(MessageId(1)));
+
+        // Without a window, every delivery counts as the first one
+        let mut cmds = Commands::new();
+        cmds.dedup_window = Duration::from_secs(0);
+        assert!(!cmds.is_repeat(MessageId(1)));
+        assert!(!cmds.is_repeat(MessageId(1)));
+
+        // Once full, the oldest message is forgotten first
+        let mut cmds = Commands::new();
+        cmds.dedup_capacity = 2;
+        for id in 1..=3 {
+            assert!(!cmds.is_repeat(MessageId(id)));
+        }
+        assert!(cmds.is_repeat(MessageId(3)));
+        assert!(!cmds.is_repeat(MessageId(1)));
+    }
+
+    #[test]
+    fn limits_argument_lengths_in_characters() {
+        let mut cmds = Commands::new();
+        let remind = cmds.add("remind", noop, "", noop);
+        remind.params = &["in"];
+        remind.body = Body::Required("text");
+        remind.max_argument_length = 3;
+        let truncate = cmds.add("note", noop, "", noop);
+        truncate.params = &["in"];
+        truncate.body = Body::Required("text");
+        truncate.max_argument_length = 3;
+        truncate.truncate_long_arguments = true;
+        let remind = cmds.find_top_level("remind").unwrap();
+        let note = cmds.find_top_level("note").unwrap();
+
+        // Exactly at the limit, even though it takes more bytes than that
+        let parsed = parse_message(&remind, "in=née 🦀🦀🦀");
+        assert_eq!(parsed.too_long, None);
+        assert_eq!(parsed.params["in"], "née");
+        assert_eq!(parsed.body, "🦀🦀🦀");
+
+        assert_eq!(parse_message(&remind, "in=nées x").too_long, Some("in"));
+        assert_eq!(parse_message(&remind, "🦀🦀🦀🦀").too_long, Some("text"));
+
+        // Cut after the last character that fits, never inside one
+        let parsed = parse_message(&note, "in=éééé 🦀🦀🦀🦀");
+        assert_eq!(parsed.too_long, None);
+        assert_eq!(parsed.params["in"], "ééé");
+        assert_eq!(parsed.params_in_order, [("in", "ééé")]);
+        assert_eq!(parsed.body, "🦀🦀🦀");
+    }
+
+    #[test]
+    fn splits_multi_byte_text_between_characters() {
+        assert_eq!(truncate_to("名前🦀é", 4), None);
+        assert_eq!(truncate_to("名前🦀é", 3), Some("名前🦀"));
+        assert_eq!(truncate_to("🦀🦀", 1), Some("🦀"));
+        assert_eq!(truncate_to("🦀", 0), Some(""));
+
+        assert_eq!(split_command_name("名前\u{3000}🦀 x"), ("名前", "🦀 x"));
+        assert_eq!(split_command_name("🦀"), ("🦀", ""));
+
+        assert_eq!(
+            split_param(&["名前=José"], false),
+            Some(("名前", "José", 1))
+        );
+        assert_eq!(
+            split_param(&["名前", "=", "🦀"], true),
+            Some(("名前", "🦀", 3))
+        );
+        assert_eq!(split_param(&["名前", "=🦀"], true), Some(("名前", "🦀", 2)));
+        assert_eq!(split_param(&["🦀=1"], false), None);
+    }
+
+    #[test]
+    fn guild_prefixes_work_along_with_the_others() {
+        let cmds = commands();
+        let (symbol_guild, letter_guild) = (GuildId(1), GuildId(2));
+        cmds.guild_prefixes()
+            .set(symbol_guild, Some("!".to_owned()))
+            .unwrap();
+        cmds.guild_prefixes()
+            .set(letter_guild, Some("h".to_owned()))
+            .unwrap();
+        let name = |guild_id, content: &str| {
+            let (command, invoked, _) = cmds.parse(Some(guild_id), Some(UserId(7)), content)?;
+            Some((command.name.to_string(), invoked.to_owned()))
+        };
+        let found = |command: &str| Some((command.to_owned(), command.to_owned()));
+
+        for &guild_id in &[symbol_guild, letter_guild] {
+            assert_eq!(name(guild_id, "?crate serde"), found("crate"));
+            assert_eq!(name(guild_id, "?"), found("help"));
+            assert_eq!(name(guild_id, "<@7> crate serde"), found("crate"));
+            assert_eq!(name(guild_id, "🦀 crate serde"), found("crate"));
+        }
+
+        assert_eq!(name(symbol_guild, "!crate serde"), found("crate"));
+        assert_eq!(name(symbol_guild, "! crate serde"), found("crate"));
+        assert_eq!(name(symbol_guild, "!"), found("help"));
+
+        // A spelled-out prefix has to be followed by the command name right away
+        assert_eq!(name(letter_guild, "hcrate serde"), found("crate"));
+        assert_eq!(name(letter_guild, "h crate serde"), None);
+        assert_eq!(name(letter_guild, "h"), None);
+        // and doesn't get in the way of the spoken prefixes
+        assert_eq!(
+            name(letter_guild, "hey ferris can you crate serde"),
+            found("crate")
+        );
+        assert_eq!(name(letter_guild, "hey ferris can you "), None);
+    }
+
+    #[test]
+    fn help_footer_has_the_usage_and_example() {
+        let mut cmds = commands();
+        let cleanup = cmds.add("cleanup", noop, "", noop);
+        cleanup.example = Some("?cleanup 10");
+        let crate_ = cmds.find_top_level("crate").unwrap();
+        let cleanup = cmds.find_top_level("cleanup").unwrap();
+        let tags = cmds.find_top_level("tags").unwrap();
+
+        assert_eq!(
+            crate_.help_footer("?", "crate").as_deref(),
+            Some("Usage: `?crate <query>`")
+        );
+        // Examples use the prefix of the guild
+        assert_eq!(
+            cleanup.help_footer("!", "cleanup").as_deref(),
+            Some("Example: `!cleanup 10`")
+        );
+        assert_eq!(tags.help_footer("?", "tags"), None);
+    }
+}
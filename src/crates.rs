@@ -1,15 +1,39 @@
-use crate::{api, commands::Args, Error};
-
+use crate::{
+    api,
+    commands::{Args, CommandError},
+    Error,
+};
+
+use indexmap::IndexMap;
+use lazy_static::lazy_static;
 use reqwest::header;
 use serde::Deserialize;
+use serenity::prelude::Mutex;
 
 const USER_AGENT: &str = "rust-lang/discord-mods-bot";
 
+/// Appended to results served from `LAST_KNOWN_GOOD`
+const STALE_NOTE: &str = "(possibly stale, crates.io is unreachable)";
+
+/// How many crates `LAST_KNOWN_GOOD` keeps, the least recently used ones are forgotten first
+const LAST_KNOWN_GOOD_CAPACITY: usize = 500;
+
+lazy_static! {
+    /// The last successful lookups by normalized crate name, see `cache_key`, served while
+    /// crates.io is down. Least recently used first
+    static ref LAST_KNOWN_GOOD: Mutex<IndexMap<String, Crate>> = Mutex::new(IndexMap::new());
+}
+
+/// Crate names are case-insensitive, and `-` and `_` are the same in them
+fn cache_key(name: &str) -> String {
+    name.to_ascii_lowercase().replace('_', "-")
+}
+
 #[derive(Debug, Deserialize)]
 struct Crates {
     crates: Vec<Crate>,
 }
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 struct Crate {
     id: String,
     name: String,
@@ -23,52 +47,106 @@ struct Crate {
 }
 
 /// Queries the crates.io crates list and yields the first result, if any
-fn get_crate(http: &reqwest::blocking::Client, query: &str) -> Result<Option<Crate>, Error> {
+fn get_crate(args: &Args, query: &str) -> Result<Option<Crate>, Error> {
     info!("searching for crate `{}`", query);
 
-    let crate_list = http
-        .get("https://crates.io/api/v1/crates")
-        .header(header::USER_AGENT, USER_AGENT)
-        .query(&[("q", query)])
-        .send()?
-        .json::<Crates>()?;
+    let crate_list = api::send_with_retry(args, api::RetryPolicy::default(), || {
+        args.http
+            .get("https://crates.io/api/v1/crates")
+            .header(header::USER_AGENT, USER_AGENT)
+            .query(&[("q", query)])
+            .send()
+    })?
+    .json::<Crates>()?;
 
     Ok(crate_list.crates.into_iter().next())
 }
 
+/// Like `get_crate`, but falls back to the last successful lookup of the crate named by the query
+/// if crates.io can't be reached. The returned flag is set if the result came from that fallback
+fn get_crate_or_cached(args: &Args, query: &str) -> Result<(Option<Crate>, bool), Error> {
+    lookup_or_cached(query, || get_crate(args, query))
+}
+
+/// See `get_crate_or_cached`, with `lookup` querying crates.io
+fn lookup_or_cached(
+    query: &str,
+    lookup: impl FnOnce() -> Result<Option<Crate>, Error>,
+) -> Result<(Option<Crate>, bool), Error> {
+    match lookup() {
+        Ok(Some(crate_)) => {
+            let mut cache = LAST_KNOWN_GOOD.lock();
+            // Reinserted at the end, as the most recently used
+            let key = cache_key(&crate_.name);
+            cache.shift_remove(&key);
+            cache.insert(key, crate_.clone());
+            if cache.len() > LAST_KNOWN_GOOD_CAPACITY {
+                cache.shift_remove_index(0);
+            }
+            Ok((Some(crate_), false))
+        }
+        Ok(None) => Ok((None, false)),
+        Err(e) => {
+            let mut cache = LAST_KNOWN_GOOD.lock();
+            match cache.shift_remove(&cache_key(query.trim())) {
+                Some(mut crate_) => {
+                    warn!("crates.io lookup failed, serving cached result: {}", e);
+                    cache.insert(cache_key(&crate_.name), crate_.clone());
+                    // It may have been cached by another query, but this one named it exactly
+                    crate_.exact_match = true;
+                    Ok((Some(crate_), true))
+                }
+                None => Err(e),
+            }
+        }
+    }
+}
+
 pub fn search(args: &Args) -> Result<(), Error> {
     if let Some(url) = rustc_crate_link(args.body) {
         return api::send_reply(args, url);
     }
 
-    match get_crate(&args.http, args.body)? {
+    let (crate_, stale) = get_crate_or_cached(args, args.body)?;
+    match crate_ {
         Some(crate_) => {
             if crate_.exact_match {
-                args.msg.channel_id.send_message(&args.cx, |m| {
-                    m.embed(|e| {
-                        e.title(&crate_.name)
-                            .url(format!("https://crates.io/crates/{}", crate_.id))
-                            .description(&crate_.description)
-                            .field("Version", &crate_.newest_version, true)
-                            .field("Downloads", &crate_.downloads, true)
-                            .timestamp(crate_.updated_at.as_str())
-                    })
+                api::send_embed(args, |e| {
+                    e.title(&crate_.name)
+                        .url(format!("https://crates.io/crates/{}", crate_.id))
+                        .description(&crate_.description)
+                        .field("Version", &crate_.newest_version, true)
+                        .field("Downloads", &crate_.downloads, true)
+                        .timestamp(crate_.updated_at.as_str());
+                    if stale {
+                        e.footer(|f| f.text(STALE_NOTE));
+                    }
+                    e
                 })?;
             } else {
-                api::send_reply(
-                    args,
-                    &format!(
-                        "Crate `{}` not found. Did you mean `{}`?",
-                        args.body, crate_.name
-                    ),
-                )?;
+                let reply = format!(
+                    "Crate `{}` not found. Did you mean `{}`?",
+                    args.body, crate_.name
+                );
+                api::send_reply(args, &with_stale_note(reply, stale))?;
             }
         }
-        None => api::send_reply(args, &format!("Crate `{}` not found", args.body))?,
+        None => {
+            let reply = format!("Crate `{}` not found", args.body);
+            return Err(CommandError::NotFound(reply).into());
+        }
     };
     Ok(())
 }
 
+/// Mark the reply as possibly outdated if it was built from `LAST_KNOWN_GOOD`
+fn with_stale_note(reply: String, stale: bool) -> String {
+    match stale {
+        true => format!("{} {}", reply, STALE_NOTE),
+        false => reply,
+    }
+}
+
 /// Provide the documentation link to an official Rust crate (e.g. std, alloc, nightly)
 fn rustc_crate_link(crate_name: &str) -> Option<&'static str> {
     match crate_name.to_ascii_lowercase().as_str() {
@@ -88,14 +166,20 @@ pub fn doc_search(args: &Args) -> Result<(), Error> {
     let mut query_iter = args.body.splitn(2, "::");
     let crate_name = query_iter.next().unwrap();
 
+    let mut stale = false;
     // The base docs url, e.g. `https://docs.rs/syn` or `https://doc.rust-lang.org/stable/std/`
     let mut doc_url = if let Some(rustc_crate) = rustc_crate_link(crate_name) {
         rustc_crate.to_string()
     } else {
-        let crate_ = match get_crate(&args.http, crate_name)? {
+        let (crate_, from_cache) = get_crate_or_cached(args, crate_name)?;
+        let crate_ = match crate_ {
             Some(x) => x,
-            None => return api::send_reply(args, &format!("Crate `{}` not found", crate_name)),
+            None => {
+                let reply = format!("Crate `{}` not found", crate_name);
+                return Err(CommandError::NotFound(reply).into());
+            }
         };
+        stale = from_cache;
 
         let crate_name = crate_.name;
         crate_
@@ -107,28 +191,58 @@ pub fn doc_search(args: &Args) -> Result<(), Error> {
         doc_url += "?search=";
         doc_url += item_path;
     }
-
-    api::send_reply(args, &doc_url)?;
+    api::send_reply(args, &with_stale_note(doc_url, stale))?;
 
     Ok(())
 }
 
 /// Print the help message
 pub fn help(args: &Args) -> Result<(), Error> {
-    let help_string = "search for a crate on crates.io
-```
-?crate query...
-```";
-    api::send_reply(args, &help_string)?;
+    let help_string = "search for a crate on crates.io";
+    api::send_reply(args, help_string)?;
     Ok(())
 }
 
 /// Print the help message
 pub fn doc_help(args: &Args) -> Result<(), Error> {
-    let help_string = "retrieve documentation for a given crate
-```
-?docs crate_name...
-```";
-    api::send_reply(args, &help_string)?;
+    let help_string = "retrieve documentation for a given crate";
+    api::send_reply(args, help_string)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crate_named(name: &str, exact_match: bool) -> Crate {
+        Crate {
+            id: name.to_owned(),
+            name: name.to_owned(),
+            newest_version: "1.0.0".to_owned(),
+            updated_at: String::new(),
+            downloads: 0,
+            description: String::new(),
+            documentation: None,
+            exact_match,
+        }
+    }
+
+    #[test]
+    fn serves_the_last_known_crate_while_crates_io_is_down() {
+        let unreachable = || Err("crates.io is down".into());
+
+        // A fuzzy query caches the crate it found under its name
+        let found = lookup_or_cached("stale-serd", || Ok(Some(crate_named("stale_serde", false))));
+        assert!(!found.unwrap().1);
+        assert!(lookup_or_cached("stale-serd", unreachable).is_err());
+
+        let (crate_, stale) = lookup_or_cached("Stale-Serde", unreachable).unwrap();
+        let crate_ = crate_.unwrap();
+        assert!(stale);
+        assert_eq!(crate_.name, "stale_serde");
+        // Named exactly this time, so it's no "did you mean"
+        assert!(crate_.exact_match);
+        let reply = with_stale_note("https://docs.rs/stale_serde".to_owned(), stale);
+        assert!(reply.ends_with(STALE_NOTE));
+    }
+}
@@ -81,11 +81,14 @@ pub fn godbolt(args: &crate::Args) -> Result<(), crate::Error> {
 pub fn help(args: &crate::Args) -> Result<(), crate::Error> {
     crate::api::send_reply(
         args,
-        "Compile Rust code using https://rust.godbolt.org. Full optimizations are applied.
-```?godbolt ``\u{200B}`
-pub fn your_function() {
+        &format!(
+            "Compile Rust code using https://rust.godbolt.org. Full optimizations are applied.
+```{}godbolt ``\u{200B}`
+pub fn your_function() {{
     // Code
-}
+}}
 ``\u{200B}` ```",
+            args.prefix
+        ),
     )
 }
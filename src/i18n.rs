@@ -0,0 +1,168 @@
+//! Catalog of the messages the bot sends on its own, as opposed to command output
+
+use crate::commands::Args;
+
+/// Languages the bot's own messages are available in. To add one, add a variant, map its language
+/// tag in `from_tag` and translate every `Text` in `Locale::text`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Locale {
+    English,
+    German,
+}
+
+/// A message of the bot, along with the values to fill into it
+#[derive(Clone, Copy, Debug)]
+pub enum Text<'a> {
+    NoPermission,
+    CommandTimedOut,
+    NoSuchCommand(&'a str),
+    /// The name that was given, and the list of similar commands
+    DidYouMean(&'a str, &'a str),
+    MissingArgument(&'a str),
+    DuplicateArgument(&'a str),
+    InvalidArgument(&'a str),
+    /// The key that was given, and the list of keys the command accepts
+    UnknownArgument(&'a str, &'a str),
+    PositionalFirst(&'a str),
+    ArgumentTooLong(&'a str),
+    CantSendDm,
+    ServiceUnavailable,
+    OnlyInChannels(&'a str),
+    NotInThisChannel,
+    CommandDisabled,
+    /// The number of seconds left
+    OnCooldown(u64),
+    /// The number of seconds left
+    ChannelOnCooldown(u64),
+    InternalError,
+    /// The largest size of an attachment in MiB
+    AttachmentTooLarge(usize),
+}
+
+impl Locale {
+    /// The preferred locale of the guild the message was sent in. English in DMs and for guilds
+    /// that aren't cached or use an unsupported locale
+    pub fn of(args: &Args) -> Self {
+        args.msg
+            .guild_id
+            .and_then(|guild_id| guild_id.to_guild_cached(args.cx))
+            .map_or(Locale::English, |guild| {
+                Locale::from_tag(&guild.read().preferred_locale)
+            })
+    }
+
+    /// Map a language tag like `en-US` or `de` to a locale
+    fn from_tag(tag: &str) -> Self {
+        match tag.split('-').next() {
+            Some("de") => Locale::German,
+            _ => Locale::English,
+        }
+    }
+
+    pub fn text(self, text: Text<'_>) -> String {
+        match self {
+            Locale::English => match text {
+                Text::NoPermission => "You don't have permission to use this command".to_owned(),
+                Text::CommandTimedOut => "Command timed out".to_owned(),
+                Text::NoSuchCommand(name) => format!("No such command `{}`", name),
+                Text::DidYouMean(name, suggestions) => {
+                    format!("No such command `{}`, did you mean {}?", name, suggestions)
+                }
+                Text::MissingArgument(key) => format!("missing argument `{}`", key),
+                Text::DuplicateArgument(key) => format!("duplicate argument `{}`", key),
+                Text::InvalidArgument(key) => format!("argument `{}` is invalid", key),
+                Text::UnknownArgument(key, expected) => {
+                    format!("unknown argument `{}`, expected one of {}", key, expected)
+                }
+                Text::PositionalFirst(name) => {
+                    format!("`{}` has to come before the key-value arguments", name)
+                }
+                Text::ArgumentTooLong(name) => format!("argument `{}` is too long", name),
+                Text::CantSendDm => {
+                    "I can't send you a DM, please allow DMs from server members and try again"
+                        .to_owned()
+                }
+                Text::ServiceUnavailable => {
+                    "The service behind this command isn't responding, please try again later"
+                        .to_owned()
+                }
+                Text::OnlyInChannels(channels) => {
+                    format!("This command can only be used in {}", channels)
+                }
+                Text::NotInThisChannel => "This command can't be used in this channel".to_owned(),
+                Text::CommandDisabled => "This command is temporarily disabled".to_owned(),
+                Text::InternalError => "An internal error occurred".to_owned(),
+                Text::AttachmentTooLarge(mib) => {
+                    format!(
+                        "The result is too large to upload, the limit is {} MiB",
+                        mib
+                    )
+                }
+                Text::OnCooldown(seconds) => format!(
+                    "You're using this command too often, please wait {} seconds",
+                    seconds
+                ),
+                Text::ChannelOnCooldown(seconds) => format!(
+                    "This command was used here too often, please wait {} seconds",
+                    seconds
+                ),
+            },
+            Locale::German => match text {
+                Text::NoPermission => "Du darfst diesen Befehl nicht verwenden".to_owned(),
+                Text::CommandTimedOut => "Zeitüberschreitung beim Ausführen des Befehls".to_owned(),
+                Text::NoSuchCommand(name) => format!("Unbekannter Befehl `{}`", name),
+                Text::DidYouMean(name, suggestions) => {
+                    format!(
+                        "Unbekannter Befehl `{}`, meintest du {}?",
+                        name, suggestions
+                    )
+                }
+                Text::MissingArgument(key) => format!("Argument `{}` fehlt", key),
+                Text::DuplicateArgument(key) => {
+                    format!("Argument `{}` wurde mehrfach angegeben", key)
+                }
+                Text::InvalidArgument(key) => format!("Argument `{}` ist ungültig", key),
+                Text::UnknownArgument(key, expected) => {
+                    format!(
+                        "Unbekanntes Argument `{}`, erwartet wird eins von {}",
+                        key, expected
+                    )
+                }
+                Text::PositionalFirst(name) => {
+                    format!("`{}` muss vor den Schlüssel-Wert-Argumenten stehen", name)
+                }
+                Text::ArgumentTooLong(name) => format!("Argument `{}` ist zu lang", name),
+                Text::CantSendDm => {
+                    "Ich kann dir keine Direktnachricht schicken, bitte erlaube Direktnachrichten \
+                     von Servermitgliedern und versuche es noch einmal"
+                        .to_owned()
+                }
+                Text::ServiceUnavailable => {
+                    "Der Dienst hinter diesem Befehl antwortet gerade nicht, bitte versuche es \
+                     später noch einmal"
+                        .to_owned()
+                }
+                Text::OnlyInChannels(channels) => {
+                    format!("Dieser Befehl kann nur in {} verwendet werden", channels)
+                }
+                Text::NotInThisChannel => {
+                    "Dieser Befehl kann in diesem Kanal nicht verwendet werden".to_owned()
+                }
+                Text::CommandDisabled => "Dieser Befehl ist vorübergehend deaktiviert".to_owned(),
+                Text::InternalError => "Ein interner Fehler ist aufgetreten".to_owned(),
+                Text::AttachmentTooLarge(mib) => format!(
+                    "Das Ergebnis ist zu groß zum Hochladen, die Grenze liegt bei {} MiB",
+                    mib
+                ),
+                Text::OnCooldown(seconds) => format!(
+                    "Du verwendest diesen Befehl zu oft, bitte warte {} Sekunden",
+                    seconds
+                ),
+                Text::ChannelOnCooldown(seconds) => format!(
+                    "Dieser Befehl wurde hier zu oft verwendet, bitte warte {} Sekunden",
+                    seconds
+                ),
+            },
+        }
+    }
+}
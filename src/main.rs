@@ -2,67 +2,148 @@
 extern crate log;
 
 mod api;
+mod audit;
 mod command_history;
 mod commands;
 mod crates;
 mod godbolt;
+mod i18n;
+mod metrics;
 mod moderation;
 mod playground;
+mod reaction_menus;
 
-use commands::{Args, Commands};
+use commands::{Args, Body, CommandError, Commands, ParamType};
 use serenity::{model::prelude::*, prelude::*};
+use std::collections::HashSet;
+use std::sync::{Arc, Weak};
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 
+/// The role of the moderators, shared with the commands through `Args::data`
+struct ModRole(RoleId);
+
 #[derive(serde::Deserialize)]
 struct Config {
     discord_token: String,
     mod_role_id: u64,
+    /// File to append a record of every command run to
+    audit_log: Option<String>,
+    /// Address to serve the command metrics on, like `127.0.0.1:9090`
+    #[cfg(feature = "metrics-server")]
+    metrics_addr: Option<String>,
+    /// File to store the command prefixes of the guilds in, see `GuildPrefixes::store_in`
+    guild_prefixes: Option<String>,
+    /// Also offer the commands as Discord slash commands
+    #[serde(default)]
+    slash_commands: bool,
+    /// IDs of the users whose commands are ignored, see `Commands::set_banned_users`
+    #[serde(default)]
+    banned_users: Vec<u64>,
+    /// IDs of the channels where no commands are run, like announcement channels
+    #[serde(default)]
+    ignored_channels: Vec<u64>,
 }
 
 fn app() -> Result<(), Error> {
+    let config = envy::from_env::<Config>()?;
     let Config {
         discord_token,
         mod_role_id,
-    } = envy::from_env::<Config>()?;
+        audit_log,
+        guild_prefixes,
+        slash_commands,
+        banned_users,
+        ignored_channels,
+        ..
+    } = config;
 
     info!("starting...");
 
-    let mut cmds = Commands::new();
+    let audit_sink = match audit_log {
+        Some(audit_log) => Some(audit::FileAuditSink::open(audit_log)?),
+        None => None,
+    };
+    let cmds = Arc::new_cyclic(|registry| {
+        let mut cmds = Commands::new();
+        cmds.set_data(ModRole(RoleId(mod_role_id)));
+        if let Some(audit_sink) = audit_sink {
+            cmds.audit(audit_sink);
+        }
+        let ignored_channels: HashSet<_> = ignored_channels.into_iter().map(ChannelId).collect();
+        cmds.before(move |args| Ok(!ignored_channels.contains(&args.msg.channel_id)));
+        register_commands(&mut cmds, registry);
+        cmds
+    });
+
+    if let Some(guild_prefixes) = guild_prefixes {
+        cmds.guild_prefixes().store_in(guild_prefixes)?;
+    }
+    cmds.set_banned_users(banned_users.into_iter().map(UserId).collect());
+    #[cfg(feature = "metrics-server")]
+    {
+        if let Some(metrics_addr) = &config.metrics_addr {
+            cmds.serve_metrics(metrics_addr.as_str())?;
+        }
+    }
 
-    cmds.add(
+    cmds.compile();
+    if slash_commands {
+        let http = serenity::http::Http::new_with_token(&format!("Bot {}", discord_token));
+        let application = http.get_current_application_info()?;
+        cmds.register_slash_commands(&discord_token, application.id.0)?;
+    }
+    Client::new_with_extras(&discord_token, |e| e.event_handler(Events { cmds }))?.start()?;
+    Ok(())
+}
+
+/// Register all commands. `registry` is where they end up, for the commands that configure the
+/// others, see `configure`
+fn register_commands(cmds: &mut Commands, registry: &Weak<Commands>) {
+    let crate_ = cmds.add_in_category(
+        "Crates",
         "crate",
         crates::search,
         "Lookup crates on crates.io",
         crates::help,
-    )
-    .broadcast_typing = true;
+    );
+    crate_.broadcast_typing = true;
+    crate_.body = Body::Required("query...");
+    crate_.example = Some("?crate serde");
 
-    cmds.add(
+    let docs = cmds.add_in_category(
+        "Crates",
         "docs",
         crates::doc_search,
         "Lookup documentation",
         crates::doc_help,
-    )
-    .broadcast_typing = true;
+    );
+    docs.broadcast_typing = true;
+    docs.body = Body::Required("crate_name...");
+    docs.example = Some("?docs std::mem::swap");
 
-    cmds.add(
+    let play = cmds.add_in_category(
+        "Rust code",
         "play",
         playground::play,
         "Compile and run rust code in a playground",
         |args| playground::play_and_eval_help(args, "play"),
-    )
-    .broadcast_typing = true;
+    );
+    play.broadcast_typing = true;
+    play.directives = Some(playground::flag_directives);
 
-    cmds.add(
+    let eval = cmds.add_in_category(
+        "Rust code",
         "eval",
         playground::eval,
         "Evaluate a single rust expression",
         |args| playground::play_and_eval_help(args, "eval"),
-    )
-    .broadcast_typing = true;
+    );
+    eval.broadcast_typing = true;
+    eval.directives = Some(playground::flag_directives);
 
-    cmds.add(
+    cmds.add_in_category(
+        "Rust code",
         "miri",
         playground::miri,
         "Run code and detect undefined behavior using Miri",
@@ -70,7 +151,8 @@ fn app() -> Result<(), Error> {
     )
     .broadcast_typing = true;
 
-    cmds.add(
+    cmds.add_in_category(
+        "Rust code",
         "expand",
         playground::expand_macros,
         "Expand macros to their raw desugared form",
@@ -78,7 +160,8 @@ fn app() -> Result<(), Error> {
     )
     .broadcast_typing = true;
 
-    cmds.add(
+    cmds.add_in_category(
+        "Rust code",
         "clippy",
         playground::clippy,
         "Catch common mistakes using the Clippy linter",
@@ -86,7 +169,8 @@ fn app() -> Result<(), Error> {
     )
     .broadcast_typing = true;
 
-    cmds.add(
+    cmds.add_in_category(
+        "Rust code",
         "fmt",
         playground::fmt,
         "Format code using rustfmt",
@@ -101,7 +185,8 @@ fn app() -> Result<(), Error> {
         |args| api::send_reply(args, "Evaluates Go code"),
     );
 
-    cmds.add(
+    cmds.add_in_category(
+        "Rust code",
         "godbolt",
         godbolt::godbolt,
         "View assembly using Godbolt",
@@ -109,20 +194,152 @@ fn app() -> Result<(), Error> {
     )
     .broadcast_typing = true;
 
-    cmds.add(
+    let cleanup = cmds.add(
         "cleanup",
-        move |args| moderation::cleanup(args, RoleId(mod_role_id)),
+        moderation::cleanup,
         "Deletes the bot's messages for cleanup",
         moderation::cleanup_help,
     );
+    cleanup.positional = Some("limit");
+    cleanup.param_types = &[("limit", ParamType::Int)];
+    cleanup.soft_guard = Some(Box::new(moderation::cleanup_guard));
+    cleanup.example = Some("?cleanup 10");
 
-    cmds.add(
+    let ban = cmds.add(
         "ban",
         moderation::joke_ban,
         "Bans another person",
         moderation::joke_ban_help,
-    )
-    .aliases = &["banne"];
+    );
+    ban.aliases = &["banne"];
+    ban.body = Body::Required("member");
+
+    let prefixes = cmds.guild_prefixes();
+    let config = cmds.group("config", "Configures the bot for this server");
+    let prefix = config.add(
+        "prefix",
+        move |args| moderation::set_prefix(args, &prefixes),
+        "Changes the command prefix",
+        moderation::set_prefix_help,
+    );
+    prefix.body = Body::Required("prefix");
+    prefix.example = Some("?config prefix !");
+    prefix.guard = Some(Box::new(is_mod));
+    prefix.guard_label = Some("moderators");
+
+    let list = config.add(
+        "list",
+        configure(registry, moderation::list_commands),
+        "Lists all commands, including the disabled ones",
+        moderation::list_commands_help,
+    );
+    list.guard = Some(Box::new(is_mod));
+    list.guard_label = Some("moderators");
+
+    let disable = config.add(
+        "disable",
+        configure(registry, moderation::disable_command),
+        "Disables a command until it's enabled again",
+        moderation::disable_command_help,
+    );
+    disable.positional = Some("command");
+    disable.params = &["for"];
+    disable.param_types = &[("for", ParamType::Duration)];
+    disable.example = Some("?config disable play for=1h");
+    disable.guard = Some(Box::new(is_mod));
+    disable.guard_label = Some("moderators");
+
+    let enable = config.add(
+        "enable",
+        configure(registry, moderation::enable_command),
+        "Enables a disabled command",
+        moderation::enable_command_help,
+    );
+    enable.body = Body::Required("command");
+    enable.example = Some("?config enable play");
+    enable.guard = Some(Box::new(is_mod));
+    enable.guard_label = Some("moderators");
+
+    let restrict = config.add(
+        "restrict",
+        configure(registry, moderation::restrict_command),
+        "Only lets a command be used in one channel",
+        moderation::restrict_command_help,
+    );
+    restrict.positional = Some("command");
+    restrict.params = &["channel"];
+    restrict.param_types = &[("channel", ParamType::Channel)];
+    restrict.example = Some("?config restrict play channel=#playground");
+    restrict.guard = Some(Box::new(is_mod));
+    restrict.guard_label = Some("moderators");
+
+    let block = config.add(
+        "block",
+        configure(registry, moderation::block_command),
+        "Keeps a command from being used in one channel",
+        moderation::block_command_help,
+    );
+    block.positional = Some("command");
+    block.params = &["channel"];
+    block.param_types = &[("channel", ParamType::Channel)];
+    block.example = Some("?config block play channel=#general");
+    block.guard = Some(Box::new(is_mod));
+    block.guard_label = Some("moderators");
+
+    let unrestrict = config.add(
+        "unrestrict",
+        configure(registry, moderation::unrestrict_command),
+        "Lets a command be used in every channel again",
+        moderation::unrestrict_command_help,
+    );
+    unrestrict.body = Body::Required("command");
+    unrestrict.example = Some("?config unrestrict play");
+    unrestrict.guard = Some(Box::new(is_mod));
+    unrestrict.guard_label = Some("moderators");
+
+    let ignore = config.add(
+        "ignore",
+        configure(registry, moderation::ignore_user),
+        "Ignores every command of a user",
+        moderation::ignore_user_help,
+    );
+    ignore.positional = Some("user");
+    ignore.param_types = &[("user", ParamType::User)];
+    ignore.example = Some("?config ignore @spammer");
+    ignore.guard = Some(Box::new(is_mod));
+    ignore.guard_label = Some("moderators");
+
+    let unignore = config.add(
+        "unignore",
+        configure(registry, moderation::unignore_user),
+        "Lets an ignored user use commands again",
+        moderation::unignore_user_help,
+    );
+    unignore.positional = Some("user");
+    unignore.param_types = &[("user", ParamType::User)];
+    unignore.example = Some("?config unignore @spammer");
+    unignore.guard = Some(Box::new(is_mod));
+    unignore.guard_label = Some("moderators");
+
+    let commands = config.add(
+        "commands",
+        configure(registry, moderation::set_guild_commands),
+        "Chooses the commands that can be used in this server",
+        moderation::set_guild_commands_help,
+    );
+    commands.body = Body::Optional("commands...");
+    commands.example = Some("?config commands crate docs play");
+    commands.guard = Some(Box::new(is_mod));
+    commands.guard_label = Some("moderators");
+
+    let stats = config.add(
+        "stats",
+        configure(registry, moderation::command_stats),
+        "Shows how often the commands were run",
+        moderation::command_stats_help,
+    );
+    stats.guard = Some(Box::new(is_mod));
+    stats.guard_label = Some("moderators");
 
     cmds.add(
         "source",
@@ -130,9 +347,27 @@ fn app() -> Result<(), Error> {
         "Links to the bot GitHub repo",
         |args| api::send_reply(args, "?source\n\nLinks to the bot GitHub repo"),
     );
+}
 
-    Client::new_with_extras(&discord_token, |e| e.event_handler(Events { cmds }))?.start()?;
-    Ok(())
+/// A handler for a command that configures the other commands, running `command` with the
+/// commands registered in `registry`
+fn configure(
+    registry: &Weak<Commands>,
+    command: fn(&Args, &Arc<Commands>) -> Result<(), Error>,
+) -> impl Fn(&Args) -> Result<(), Error> + Send + Sync + 'static {
+    let registry = Weak::clone(registry);
+    move |args| match registry.upgrade() {
+        Some(cmds) => command(args, &cmds),
+        None => Err("the commands are gone, the bot is shutting down".into()),
+    }
+}
+
+/// The most lines of output a reply shows, see `reply_potentially_long_text`
+const MAX_OUTPUT_LINES: usize = 45;
+
+/// Whether `reply_potentially_long_text` would send the text as it is, without truncating it
+fn fits_in_reply(text_body: &str, text_end: &str) -> bool {
+    text_body.len() + text_end.len() <= 2000 && text_body.lines().count() <= MAX_OUTPUT_LINES
 }
 
 /// Send a Discord reply message and truncate the message with a given truncation message if the
@@ -159,10 +394,13 @@ fn reply_potentially_long_text(
     text_end: &str,
     truncation_msg: &str,
 ) -> Result<(), Error> {
-    const MAX_OUTPUT_LINES: usize = 45;
+    api::send_reply(args, &truncate_reply(text_body, text_end, truncation_msg))
+}
 
+/// The reply `reply_potentially_long_text` sends
+fn truncate_reply(text_body: &str, text_end: &str, truncation_msg: &str) -> String {
     // check the 2000 char limit first, because otherwise we could produce a too large message
-    let msg = if text_body.len() + text_end.len() > 2000 {
+    if text_body.len() + text_end.len() > 2000 {
         // This is how long the text body may be at max to conform to Discord's limit
         let available_space = 2000 - text_end.len() - truncation_msg.len();
 
@@ -190,13 +428,14 @@ fn reply_potentially_long_text(
         )
     } else {
         format!("{}{}", text_body, text_end)
-    };
-
-    api::send_reply(args, &msg)
+    }
 }
 
 /// Extract code from a Discord code block on a best-effort basis
 ///
+/// Like Discord, inline code ends at the first run of as many backticks as it was opened with,
+/// so a longer fence can contain backticks, and anything after the closing fence is ignored.
+///
 /// ```rust
 /// assert_eq!(extract_code("`hello`"), Some("hello"));
 /// assert_eq!(extract_code("`    hello `"), Some("hello"));
@@ -206,33 +445,72 @@ fn reply_potentially_long_text(
 /// assert_eq!(extract_code("``` rust\nhello\n```"), Some("rust\nhello"));
 /// ```
 pub fn extract_code(input: &str) -> Result<&str, Error> {
-    fn inner(input: &str) -> Option<&str> {
+    Ok(extract_code_and_lang(input)?.1)
+}
+
+/// Extract code from a Discord code block like `extract_code`, along with the language tag of
+/// multi-line code blocks, if there is one
+///
+/// ```rust
+/// assert_eq!(extract_code_and_lang("`hello`").unwrap(), (None, "hello"));
+/// assert_eq!(extract_code_and_lang("```py\nhello\n```").unwrap(), (Some("py"), "hello"));
+/// assert_eq!(extract_code_and_lang("```\nhello\n```").unwrap(), (None, "hello"));
+/// ```
+pub fn extract_code_and_lang(input: &str) -> Result<(Option<&str>, &str), Error> {
+    fn inner(input: &str) -> Option<(Option<&str>, &str)> {
         let input = input.trim();
 
-        let extracted_code = if input.starts_with("```") && input.ends_with("```") {
+        let (lang, extracted_code) = if input.starts_with("```") && input.ends_with("```") {
             let code_starting_point = input.find(char::is_whitespace)?; // skip over lang specifier
             let code_end_point = input.len() - 3;
 
+            let lang = Some(&input[3..code_starting_point]).filter(|lang| !lang.is_empty());
             // can't fail but you can never be too sure
-            input.get(code_starting_point..code_end_point)?
-        } else if input.starts_with('`') && input.ends_with('`') {
-            // can't fail but you can never be too sure
-            input.get(1..(input.len() - 1))?
+            (lang, input.get(code_starting_point..code_end_point)?)
+        } else if input.starts_with('`') {
+            let code = input.trim_start_matches('`');
+            let fence_len = input.len() - code.len();
+            (None, &code[..closing_fence(code, fence_len)?])
         } else {
             return None;
         };
 
-        Some(extracted_code.trim())
+        Some((lang, extracted_code.trim()))
     }
 
-    Ok(inner(input).ok_or(
-        "Missing code block. Please use the following markdown:
+    Ok(inner(input).ok_or_else(|| {
+        CommandError::User(
+            "Missing code block. Please use the following markdown:
 \\`code here\\`
 or
 \\`\\`\\`rust
 code here
-\\`\\`\\`",
-    )?)
+\\`\\`\\`"
+                .to_owned(),
+        )
+    })?)
+}
+
+/// Where the first run of exactly `fence_len` backticks in `code` starts
+fn closing_fence(code: &str, fence_len: usize) -> Option<usize> {
+    let mut searched = 0;
+    while let Some(start) = code[searched..].find('`') {
+        let start = searched + start;
+        let run_len = code[start..].len() - code[start..].trim_start_matches('`').len();
+        if run_len == fence_len {
+            return Some(start);
+        }
+        searched = start + run_len;
+    }
+    None
+}
+
+/// Whether the author of the message has the mod role. Nobody is a mod in DMs
+pub fn is_mod(args: &Args) -> Result<bool, Error> {
+    Ok(match (&args.msg.member, args.data::<ModRole>()) {
+        (Some(member), Some(ModRole(mod_role_id))) => member.roles.contains(mod_role_id),
+        _ => false,
+    })
 }
 
 pub fn find_custom_emoji(args: &Args, emoji_name: &str) -> Option<Emoji> {
@@ -279,7 +557,7 @@ impl TypeMapKey for BotUserId {
 }
 
 struct Events {
-    cmds: Commands,
+    cmds: Arc<Commands>,
 }
 
 impl EventHandler for Events {
@@ -288,6 +566,7 @@ impl EventHandler for Events {
         {
             let mut data = cx.data.write();
             data.insert::<command_history::CommandHistory>(indexmap::IndexMap::new());
+            data.insert::<reaction_menus::ReactionMenus>(std::collections::HashMap::new());
             data.insert::<BotUserId>(ready.user.id);
         }
 
@@ -306,16 +585,18 @@ impl EventHandler for Events {
     fn message_update(
         &self,
         cx: Context,
-        _: Option<Message>,
+        old: Option<Message>,
         _: Option<Message>,
         ev: MessageUpdateEvent,
     ) {
-        if let Err(e) = command_history::replay_message(cx, ev, &self.cmds) {
+        if let Err(e) = command_history::replay_message(cx, old, ev, &self.cmds) {
             error!("{}", e);
         }
     }
 
     fn message_delete(&self, cx: Context, channel_id: ChannelId, message_id: MessageId) {
+        reaction_menus::remove(&cx, message_id);
+
         let mut data = cx.data.write();
         let history = data.get_mut::<command_history::CommandHistory>().unwrap();
         if let Some(response_id) = history.remove(&message_id) {
@@ -323,4 +604,77 @@ impl EventHandler for Events {
             let _ = channel_id.delete_message(&cx, response_id);
         }
     }
+
+    fn unknown(&self, cx: Context, name: String, raw: serde_json::Value) {
+        if name == "INTERACTION_CREATE" {
+            if let Err(e) = self.cmds.execute_interaction(&cx, &raw) {
+                error!("{}", e);
+            }
+        }
+    }
+
+    fn reaction_add(&self, cx: Context, reaction: Reaction) {
+        // Ignore the reactions the bot adds itself to present the menu options
+        let bot_id = *cx.data.read().get::<BotUserId>().unwrap();
+        if reaction.user_id == bot_id {
+            return;
+        }
+
+        if let Err(e) = reaction_menus::dispatch(&cx, &reaction) {
+            error!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inline_code_ends_at_the_matching_fence() {
+        assert_eq!(extract_code("`a`").unwrap(), "a");
+        assert_eq!(extract_code("``a`b``").unwrap(), "a`b");
+        assert_eq!(extract_code("`foo`bar`").unwrap(), "foo");
+    }
+
+    #[test]
+    fn mismatched_fences_are_no_code() {
+        assert!(extract_code("``a`").is_err());
+        assert!(extract_code("`a``").is_err());
+        assert_eq!(closing_fence("a`b``", 2), Some(3));
+        assert_eq!(closing_fence("a```", 2), None);
+    }
+
+    #[test]
+    fn long_output_doesnt_fit_in_a_reply() {
+        assert!(fits_in_reply(&"a".repeat(1997), "```"));
+        assert!(!fits_in_reply(&"a".repeat(1998), "```"));
+        assert!(fits_in_reply(&"a\n".repeat(45), "```"));
+        assert!(!fits_in_reply(&"a\n".repeat(46), "```"));
+    }
+
+    #[test]
+    fn long_replies_are_cut_between_characters() {
+        let (end, note) = ("\n```", "\n(truncated)");
+        let available_space = 2000 - end.len() - note.len();
+        // The limit falls on the second, third and fourth byte of a character
+        for (text, offset) in &[("🦀", 1), ("🦀", 2), ("名前", 1), ("名前", 2), ("é", 1)] {
+            let fitting = "a".repeat(available_space - offset);
+            let body = format!("{}{}", fitting, text.repeat(2000));
+            let reply = truncate_reply(&body, end, note);
+            assert_eq!(reply, format!("{}{}{}", fitting, end, note));
+        }
+
+        // A multi-byte character that ends right at the limit is kept
+        let body = format!("{}🦀{}", "a".repeat(available_space - 4), "b".repeat(100));
+        let reply = truncate_reply(&body, end, note);
+        assert!(reply.starts_with(&format!("{}🦀{}", "a".repeat(available_space - 4), end)));
+        assert_eq!(reply.len(), 2000);
+
+        let short = "名前 🦀";
+        assert_eq!(
+            truncate_reply(short, end, note),
+            format!("{}{}", short, end)
+        );
+    }
 }
@@ -0,0 +1,181 @@
+use crate::{commands::CommandError, Error};
+use serenity::prelude::*;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+pub const LATENCY_BUCKETS: [f64; 7] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// How often a command was run and how that went
+#[derive(Clone, Debug, Default)]
+pub struct CommandStats {
+    pub invocations: u64,
+    pub successes: u64,
+    pub errors: u64,
+    /// Invocations the command's guard rejected
+    pub unauthorized: u64,
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+    /// How many invocations took at most as long as the bucket in `LATENCY_BUCKETS` at the same
+    /// index. Not cumulative, each invocation is counted in the first bucket it fits into
+    pub latency_buckets: [u64; LATENCY_BUCKETS.len()],
+}
+
+impl CommandStats {
+    pub fn average_latency(&self) -> Duration {
+        match self.invocations {
+            0 => Duration::default(),
+            n => self.total_latency / n as u32,
+        }
+    }
+}
+
+/// Usage counters of all commands, keyed by the top-level command name
+#[derive(Default)]
+pub struct Metrics {
+    stats: Mutex<HashMap<String, CommandStats>>,
+}
+
+impl Metrics {
+    pub fn record(&self, command_name: &str, result: &Result<(), Error>, latency: Duration) {
+        let mut stats = self.stats.lock();
+        let stats = stats.entry(command_name.to_owned()).or_default();
+        stats.invocations += 1;
+        stats.total_latency += latency;
+        stats.max_latency = stats.max_latency.max(latency);
+        let seconds = latency.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&bound| seconds <= bound) {
+            stats.latency_buckets[bucket] += 1;
+        }
+        match result {
+            Ok(()) => stats.successes += 1,
+            Err(e) if CommandError::is_permission(e) => stats.unauthorized += 1,
+            Err(_) => stats.errors += 1,
+        }
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, CommandStats> {
+        self.stats.lock().clone()
+    }
+
+    /// The metrics in the Prometheus text exposition format
+    #[cfg(feature = "metrics-server")]
+    pub fn render_prometheus(&self) -> String {
+        use std::fmt::Write;
+
+        let mut stats: Vec<_> = self.snapshot().into_iter().collect();
+        stats.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut out = String::new();
+        // Name, description and value of each counter
+        type Counter = (&'static str, &'static str, fn(&CommandStats) -> u64);
+        let counters: [Counter; 4] = [
+            ("invocations", "Times the command was run", |s| {
+                s.invocations
+            }),
+            ("successes", "Runs that succeeded", |s| s.successes),
+            ("errors", "Runs that failed", |s| s.errors),
+            ("denials", "Runs the guard rejected", |s| s.unauthorized),
+        ];
+        for (name, help, value) in &counters {
+            let _ = writeln!(out, "# HELP bot_command_{}_total {}", name, help);
+            let _ = writeln!(out, "# TYPE bot_command_{}_total counter", name);
+            for (command, stats) in &stats {
+                let _ = writeln!(
+                    out,
+                    "bot_command_{}_total{{command=\"{}\"}} {}",
+                    name,
+                    escape_label(command),
+                    value(stats)
+                );
+            }
+        }
+
+        out += "# HELP bot_command_latency_seconds How long the command took\n";
+        out += "# TYPE bot_command_latency_seconds histogram\n";
+        for (command, stats) in &stats {
+            let command = escape_label(command);
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&stats.latency_buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "bot_command_latency_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                    command, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "bot_command_latency_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}",
+                command, stats.invocations
+            );
+            let _ = writeln!(
+                out,
+                "bot_command_latency_seconds_sum{{command=\"{}\"}} {}",
+                command,
+                stats.total_latency.as_secs_f64()
+            );
+            let _ = writeln!(
+                out,
+                "bot_command_latency_seconds_count{{command=\"{}\"}} {}",
+                command, stats.invocations
+            );
+        }
+        out
+    }
+}
+
+#[cfg(feature = "metrics-server")]
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Answer `GET /metrics` with the rendered metrics on a thread of its own, until the process exits
+#[cfg(feature = "metrics-server")]
+pub fn serve(
+    metrics: std::sync::Arc<Metrics>,
+    addr: impl std::net::ToSocketAddrs,
+) -> std::io::Result<()> {
+    use std::io::{BufRead, BufReader, Write};
+
+    let listener = std::net::TcpListener::bind(addr)?;
+    info!("Serving metrics on {}", listener.local_addr()?);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Can't accept metrics connection: {}", e);
+                    continue;
+                }
+            };
+            // Requests are handled one at a time, don't let a stuck client block the others
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+            // Only the request line matters, the headers are ignored
+            let mut request_line = String::new();
+            if let Err(e) = BufReader::new(&stream).read_line(&mut request_line) {
+                warn!("Can't read metrics request: {}", e);
+                continue;
+            }
+            let response = if request_line.starts_with("GET /metrics ") {
+                let body = metrics.render_prometheus();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_owned()
+            };
+            if let Err(e) = stream.write_all(response.as_bytes()) {
+                warn!("Can't send metrics: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
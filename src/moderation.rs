@@ -1,20 +1,54 @@
-use crate::{Args, Error};
-use serenity::model::prelude::*;
+use crate::{
+    commands::{Commands, GuardOutcome, GuildPrefixes},
+    Args, CommandError, Error,
+};
+use lazy_static::lazy_static;
+use serenity::{model::prelude::*, prelude::*};
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Instant;
 
-pub fn cleanup(args: &Args, mod_role_id: RoleId) -> Result<(), Error> {
-    let num_messages = if args.body.is_empty() {
-        5
-    } else {
-        args.body.parse::<usize>()?
-    };
+/// The most messages someone who isn't a mod can clean up at once
+const CLEANUP_LIMIT: usize = 10;
+
+/// How many messages to clean up, 5 unless a limit was given
+fn cleanup_limit(args: &Args) -> Result<usize, Error> {
+    match args.params.get("limit") {
+        Some(_) => Ok(usize::try_from(args.param_int("limit")?)
+            .map_err(|_| CommandError::User("The limit can't be negative".to_owned()))?),
+        None => Ok(5),
+    }
+}
+
+/// Whether the author can clean up everything. In DMs, treat the user as an "effective" mod
+fn cleans_up_as_mod(args: &Args) -> Result<bool, Error> {
+    Ok(args.msg.guild_id.is_none() || crate::is_mod(args)?)
+}
+
+/// Let everyone clean up a few recent messages, and tell them that mods can do more
+pub fn cleanup_guard(args: &Args) -> Result<GuardOutcome, Error> {
+    if cleans_up_as_mod(args)? {
+        return Ok(GuardOutcome::Allow);
+    }
+    if cleanup_limit(args)? > CLEANUP_LIMIT {
+        return Ok(GuardOutcome::Deny(format!(
+            "Only mods can clean up more than {} messages",
+            CLEANUP_LIMIT
+        )));
+    }
+    Ok(GuardOutcome::AllowWithNotice(
+        "Only messages from the last 24 hours were looked at, mods can clean up older ones"
+            .to_owned(),
+    ))
+}
+
+pub fn cleanup(args: &Args) -> Result<(), Error> {
+    let num_messages = cleanup_limit(args)?;
 
     info!("Cleaning up {} messages", num_messages);
 
-    let is_mod = match &args.msg.member {
-        Some(member) => member.roles.contains(&mod_role_id),
-        None => true, // in DMs, treat the user as an "effective" mod
-    };
+    let is_mod = cleans_up_as_mod(args)?;
     let data = args.cx.data.read();
     let bot_id = *data.get::<crate::BotUserId>().unwrap();
 
@@ -37,17 +71,19 @@ pub fn cleanup(args: &Args, mod_role_id: RoleId) -> Result<(), Error> {
         .take(num_messages)
         .try_for_each(|msg| msg.delete(&args.cx.http))?;
 
-    crate::react_custom_emoji(args, "rustOk", '👌')
+    match args.notice {
+        // A reaction can't carry the notice
+        Some(_) => crate::api::send_reply(args, &crate::custom_emoji_code(args, "rustOk", '👌')),
+        None => crate::react_custom_emoji(args, "rustOk", '👌'),
+    }
 }
 
 pub fn cleanup_help(args: &Args) -> Result<(), Error> {
     crate::api::send_reply(
         args,
-        "?cleanup [limit]
-
-Deletes the bot's messages for cleanup.
-You can specify how many messages to look for. Only messages from the last 24 hours can be deleted,
-except for mods",
+        "Deletes the bot's messages for cleanup.
+You can specify how many messages to look for, up to 10 unless you're a mod. Only messages from the
+last 24 hours can be deleted, except for mods",
     )
 }
 
@@ -132,10 +168,326 @@ pub fn joke_ban(args: &Args) -> Result<(), Error> {
 }
 
 pub fn joke_ban_help(args: &Args) -> Result<(), Error> {
+    crate::api::send_reply(args, "Bans another person")
+}
+
+/// The longest prefix a guild can have
+const MAX_PREFIX_LENGTH: usize = 8;
+
+pub fn set_prefix(args: &Args, prefixes: &GuildPrefixes) -> Result<(), Error> {
+    let guild_id = match args.msg.guild_id {
+        Some(x) => x,
+        None => return Err(CommandError::User("Only servers can have a prefix".to_owned()).into()),
+    };
+    let prefix = args.body;
+    if prefix.chars().count() > MAX_PREFIX_LENGTH || prefix.contains(char::is_whitespace) {
+        let reply = format!(
+            "The prefix can't have spaces or more than {} characters",
+            MAX_PREFIX_LENGTH
+        );
+        return Err(CommandError::User(reply).into());
+    }
+
+    prefixes.set(
+        guild_id,
+        Some(prefix.to_owned()).filter(|prefix| prefix != "?"),
+    )?;
+    let reply = match prefix {
+        "?" => "Commands start with `?` only again".to_owned(),
+        _ => format!(
+            "Commands can start with `{}` now, as well as with `?`",
+            prefix
+        ),
+    };
+    crate::api::send_reply(args, &reply)
+}
+
+pub fn set_prefix_help(args: &Args) -> Result<(), Error> {
+    crate::api::send_reply(
+        args,
+        &format!(
+            "Adds a prefix that starts commands in this server, along with `?`.
+Use `{}config prefix ?` to only use `?` again. Mentioning the bot always works as a prefix.
+A prefix ending in a letter or digit has to be followed by the command name without a space.",
+            args.prefix
+        ),
+    )
+}
+
+/// Lines of `?config list` per page
+const LIST_PAGE_LINES: usize = 20;
+
+pub fn list_commands(args: &Args, cmds: &Arc<Commands>) -> Result<(), Error> {
+    let report = cmds.describe();
+    let lines: Vec<_> = report.lines().collect();
+    let pages = lines
+        .chunks(LIST_PAGE_LINES)
+        .map(|lines| lines.join("\n"))
+        .collect();
+    crate::api::send_pages(args, pages, true)
+}
+
+pub fn list_commands_help(args: &Args) -> Result<(), Error> {
     crate::api::send_reply(
         args,
-        "?ban <member>
+        "Lists every command with its category, who may use it, its cooldown and whether it's
+disabled. Unlike the help menu, this includes the commands you can't use.",
+    )
+}
+
+lazy_static! {
+    /// Until when the commands disabled with `?config disable … for=…` stay disabled
+    static ref DISABLED_UNTIL: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+}
+
+/// The name of the top-level command `name` refers to, which may be an alias
+fn command_root(cmds: &Commands, name: &str) -> Result<String, Error> {
+    cmds.manifest()
+        .into_iter()
+        .find(|info| {
+            info.name.eq_ignore_ascii_case(name)
+                || info
+                    .aliases
+                    .iter()
+                    .any(|alias| alias.eq_ignore_ascii_case(name))
+        })
+        .map(|info| info.name)
+        .ok_or_else(|| CommandError::NotFound(format!("There's no command `{}`", name)).into())
+}
+
+pub fn disable_command(args: &Args, cmds: &Arc<Commands>) -> Result<(), Error> {
+    let root = command_root(cmds, args.param("command")?)?;
+    if root == "config" {
+        let reply = "`?config` can't be disabled, it couldn't be enabled again".to_owned();
+        return Err(CommandError::User(reply).into());
+    }
+    let duration = match args.params.get("for") {
+        Some(_) => Some(args.param_duration("for")?),
+        None => None,
+    };
+
+    cmds.disable(&root, true);
+    let until = duration.map(|duration| Instant::now() + duration);
+    match until {
+        Some(until) => DISABLED_UNTIL.lock().insert(root.clone(), until),
+        None => DISABLED_UNTIL.lock().remove(&root),
+    };
+    if let (Some(duration), Some(until)) = (duration, until) {
+        let cmds = Arc::downgrade(cmds);
+        let root = root.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(duration);
+            // Unless it was enabled or disabled again in the meantime
+            let mut disabled_until = DISABLED_UNTIL.lock();
+            if disabled_until.get(&root) == Some(&until) {
+                disabled_until.remove(&root);
+                if let Some(cmds) = cmds.upgrade() {
+                    cmds.enable(&root);
+                }
+            }
+        });
+    }
+    crate::api::send_reply(args, &format!("`?{}` is disabled", root))
+}
+
+pub fn disable_command_help(args: &Args) -> Result<(), Error> {
+    crate::api::send_reply(
+        args,
+        "Disables a command, e.g. because it misbehaves, until it's enabled again. Give a duration \
+like `for=1h` to enable it again after that long. Whoever uses it meanwhile is told that it's \
+disabled.",
+    )
+}
+
+pub fn enable_command(args: &Args, cmds: &Arc<Commands>) -> Result<(), Error> {
+    let root = command_root(cmds, args.body)?;
+    DISABLED_UNTIL.lock().remove(&root);
+    let reply = match cmds.enable(&root) {
+        true => format!("`?{}` is enabled again", root),
+        false => format!("`?{}` wasn't disabled", root),
+    };
+    crate::api::send_reply(args, &reply)
+}
+
+pub fn enable_command_help(args: &Args) -> Result<(), Error> {
+    crate::api::send_reply(args, "Enables a command disabled with `?config disable`")
+}
+
+pub fn restrict_command(args: &Args, cmds: &Arc<Commands>) -> Result<(), Error> {
+    let root = command_root(cmds, args.param("command")?)?;
+    let channel_id = args.param_channel("channel")?;
+    cmds.restrict_to_channels(&root, vec![channel_id], true);
+    crate::api::send_reply(
+        args,
+        &format!(
+            "`?{}` can only be used in {} now",
+            root,
+            channel_id.mention()
+        ),
+    )
+}
 
-Bans another person",
+pub fn restrict_command_help(args: &Args) -> Result<(), Error> {
+    crate::api::send_reply(
+        args,
+        "Only lets a command be used in one channel. Whoever uses it elsewhere is told where to use \
+it. Replaces an earlier `?config restrict` or `?config block` of the command.",
+    )
+}
+
+pub fn block_command(args: &Args, cmds: &Arc<Commands>) -> Result<(), Error> {
+    let root = command_root(cmds, args.param("command")?)?;
+    let channel_id = args.param_channel("channel")?;
+    cmds.block_in_channels(&root, vec![channel_id], true);
+    crate::api::send_reply(
+        args,
+        &format!("`?{}` can't be used in {} now", root, channel_id.mention()),
+    )
+}
+
+pub fn block_command_help(args: &Args) -> Result<(), Error> {
+    crate::api::send_reply(
+        args,
+        "Keeps a command from being used in one channel. Replaces an earlier `?config restrict` or \
+`?config block` of the command.",
+    )
+}
+
+pub fn unrestrict_command(args: &Args, cmds: &Arc<Commands>) -> Result<(), Error> {
+    let root = command_root(cmds, args.body)?;
+    let reply = match cmds.unrestrict_channels(&root) {
+        true => format!("`?{}` can be used in every channel again", root),
+        false => format!("`?{}` wasn't restricted to any channels", root),
+    };
+    crate::api::send_reply(args, &reply)
+}
+
+pub fn unrestrict_command_help(args: &Args) -> Result<(), Error> {
+    crate::api::send_reply(
+        args,
+        "Lifts `?config restrict` and `?config block` from a command",
+    )
+}
+
+pub fn ignore_user(args: &Args, cmds: &Arc<Commands>) -> Result<(), Error> {
+    let user_id = args.param_user("user")?;
+    if user_id == args.msg.author.id {
+        return Err(CommandError::User("You can't ignore yourself".to_owned()).into());
+    }
+    let mut banned_users = cmds.banned_users();
+    banned_users.insert(user_id);
+    cmds.set_banned_users(banned_users);
+    crate::api::send_reply(args, &format!("Ignoring {} from now on", user_id.mention()))
+}
+
+pub fn ignore_user_help(args: &Args) -> Result<(), Error> {
+    crate::api::send_reply(
+        args,
+        "Ignores every command of a user, in all servers, until `?config unignore`. The users in \
+`BANNED_USERS` are ignored again after a restart, the others are forgotten.",
+    )
+}
+
+pub fn unignore_user(args: &Args, cmds: &Arc<Commands>) -> Result<(), Error> {
+    let user_id = args.param_user("user")?;
+    let mut banned_users = cmds.banned_users();
+    let reply = match banned_users.remove(&user_id) {
+        true => format!("{} can use commands again", user_id.mention()),
+        false => format!("{} wasn't ignored", user_id.mention()),
+    };
+    cmds.set_banned_users(banned_users);
+    crate::api::send_reply(args, &reply)
+}
+
+pub fn unignore_user_help(args: &Args) -> Result<(), Error> {
+    crate::api::send_reply(
+        args,
+        "Lets a user ignored with `?config ignore` use commands again",
+    )
+}
+
+pub fn set_guild_commands(args: &Args, cmds: &Arc<Commands>) -> Result<(), Error> {
+    let guild_id = match args.msg.guild_id {
+        Some(x) => x,
+        None => {
+            let reply = "Only servers can have a set of commands".to_owned();
+            return Err(CommandError::User(reply).into());
+        }
+    };
+    if args.body.is_empty() {
+        let reply = match cmds.clear_guild_commands(Some(guild_id)) {
+            true => "All commands can be used in this server again",
+            false => "All commands can already be used in this server",
+        };
+        return crate::api::send_reply(args, reply);
+    }
+
+    let mut roots = args
+        .body
+        .split_whitespace()
+        .map(|name| command_root(cmds, name))
+        .collect::<Result<Vec<_>, _>>()?;
+    // Otherwise there'd be no way back
+    if !roots.iter().any(|root| root == "config") {
+        roots.push("config".to_owned());
+    }
+    let roots: Vec<_> = roots.iter().map(String::as_str).collect();
+    cmds.set_guild_commands(Some(guild_id), &roots);
+    let list = roots
+        .iter()
+        .map(|root| format!("`?{}`", root))
+        .collect::<Vec<_>>()
+        .join(", ");
+    crate::api::send_reply(
+        args,
+        &format!("Only {} can be used in this server now", list),
+    )
+}
+
+pub fn set_guild_commands_help(args: &Args) -> Result<(), Error> {
+    crate::api::send_reply(
+        args,
+        "Only lets the given commands be used in this server, the others are ignored. `?config` and \
+the help menu always stay. Without any commands, all of them can be used again.",
+    )
+}
+
+pub fn command_stats(args: &Args, cmds: &Arc<Commands>) -> Result<(), Error> {
+    let mut stats: Vec<_> = cmds.metrics_snapshot().into_iter().collect();
+    // Most used first
+    stats.sort_by(|(a_name, a), (b_name, b)| {
+        b.invocations
+            .cmp(&a.invocations)
+            .then_with(|| a_name.cmp(b_name))
+    });
+
+    let mut reply = format!(
+        "{} servers, about {} users, {} shards\n",
+        args.guild_count(),
+        args.approximate_user_count(),
+        args.shard_count()
+    );
+    if stats.is_empty() {
+        reply += "No commands were run yet";
+    }
+    for (name, stats) in &stats {
+        reply += &format!(
+            "\n`?{}`: run {} times, {} failed, {} denied, {}ms on average, {}ms at most",
+            name,
+            stats.invocations,
+            stats.errors,
+            stats.unauthorized,
+            stats.average_latency().as_millis(),
+            stats.max_latency.as_millis()
+        );
+    }
+    crate::api::send_reply(args, &reply)
+}
+
+pub fn command_stats_help(args: &Args) -> Result<(), Error> {
+    crate::api::send_reply(
+        args,
+        "Shows how often each command was run since the bot started, how that went and how long it \
+took",
     )
 }
@@ -208,6 +208,11 @@ fn parse_flags(args: &Args) -> (CommandFlags, String) {
     (flags, errors)
 }
 
+/// Lets the flags also be given as comments inside the code block, e.g. `// mode: release`
+pub fn flag_directives(body: &str) -> (Vec<(&str, &str)>, String) {
+    crate::commands::comment_directives(body, &["channel", "mode", "edition"])
+}
+
 fn generic_help(args: &Args, cmd: &str, desc: &str, full: bool) -> Result<(), Error> {
     let mut reply = format!(
         "{}. All code is executed on https://play.rust-lang.org.\n",
@@ -215,7 +220,8 @@ fn generic_help(args: &Args, cmd: &str, desc: &str, full: bool) -> Result<(), Er
     );
 
     reply += &format!(
-        "```?{} {}edition={{}} ``\u{200B}`code``\u{200B}` ```\n",
+        "```{}{} {}edition={{}} ``\u{200B}`code``\u{200B}` ```\n",
+        args.prefix,
         cmd,
         if full { "mode={} channel={} " } else { "" },
     );
@@ -226,6 +232,9 @@ fn generic_help(args: &Args, cmd: &str, desc: &str, full: bool) -> Result<(), Er
         reply += "    \tchannel: stable, beta, nightly (default: nightly)\n";
     }
     reply += "    \tedition: 2015, 2018 (default: 2018)\n";
+    if full {
+        reply += "Arguments can also be given as comments in the code, e.g. `// mode: release`\n";
+    }
 
     api::send_reply(args, &reply)
 }
@@ -351,17 +360,22 @@ fn send_reply(
         format!("{}\n{}", result.stderr, result.stdout)
     };
 
+    let reply = format!("{}```rust\n{}", flag_parse_errors, result);
     if result.trim().is_empty() {
         api::send_reply(args, &format!("{}``` ```", flag_parse_errors))
+    } else if crate::fits_in_reply(&reply, "```") {
+        api::send_reply(args, &format!("{}```", reply))
     } else {
-        crate::reply_potentially_long_text(
+        // The whole output is attached, there's no point in showing a truncated part of it
+        api::send_reply_with_attachment(
             args,
-            &format!("{}```rust\n{}", flag_parse_errors, result),
-            "```",
             &format!(
-                "Output too large. Playground link: {}",
+                "{}Output too large. Playground link: {}",
+                flag_parse_errors,
                 url_from_gist(&flags, &post_gist(args, code)?),
             ),
+            "output.txt",
+            result.as_bytes(),
         )
     }
 }
@@ -417,23 +431,30 @@ fn play_or_eval(args: &Args, result_handling: ResultHandling) -> Result<(), Erro
     let code = maybe_wrap(crate::extract_code(args.body)?, result_handling);
     let (flags, flag_parse_errors) = parse_flags(args);
 
-    let mut result: PlayResult = args
-        .http
-        .post("https://play.rust-lang.org/execute")
-        .json(&PlaygroundRequest {
-            code: &code,
-            channel: flags.channel,
-            crate_type: if code.contains("fn main") {
-                CrateType::Binary
-            } else {
-                CrateType::Library
-            },
-            edition: flags.edition,
-            mode: flags.mode,
-            tests: false,
-        })
-        .send()?
-        .json()?;
+    let request = PlaygroundRequest {
+        code: &code,
+        channel: flags.channel,
+        crate_type: if code.contains("fn main") {
+            CrateType::Binary
+        } else {
+            CrateType::Library
+        },
+        edition: flags.edition,
+        mode: flags.mode,
+        tests: false,
+    };
+    // Running the code can take up to the whole HTTP timeout, which is too long to wait for twice
+    let policy = api::RetryPolicy {
+        retry_timeouts: false,
+        ..api::RetryPolicy::default()
+    };
+    let mut result: PlayResult = api::send_with_retry(args, policy, || {
+        args.http
+            .post("https://play.rust-lang.org/execute")
+            .json(&request)
+            .send()
+    })?
+    .json()?;
 
     let compiler_warnings = extract_relevant_lines(
         &result.stderr,
@@ -0,0 +1,168 @@
+use crate::Error;
+use serenity::{model::prelude::*, prelude::*};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// Called with the reaction that was added. The reacting user is `reaction.user_id`
+pub type ReactionHandler = Arc<dyn Fn(&Context, &Reaction) -> Result<(), Error> + Send + Sync>;
+
+/// The handlers of each message, by the emoji they're for
+type Menus = HashMap<MessageId, Vec<(ReactionType, ReactionHandler, Registration)>>;
+
+/// Identifies a handler added with `register`, so that it can be removed again without touching
+/// handlers registered for the same message later on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Registration(u64);
+
+/// Reaction handlers for the bot's own messages, used to build reaction-driven menus
+pub struct ReactionMenus;
+
+impl TypeMapKey for ReactionMenus {
+    type Value = Menus;
+}
+
+/// Run `handler` whenever someone reacts with `emoji` to the bot message `message_id`
+pub fn register(
+    cx: &Context,
+    message_id: MessageId,
+    emoji: impl Into<ReactionType>,
+    handler: impl Fn(&Context, &Reaction) -> Result<(), Error> + Send + Sync + 'static,
+) -> Registration {
+    let mut data = cx.data.write();
+    let menus = data.get_mut::<ReactionMenus>().unwrap();
+    add_handler(menus, message_id, emoji.into(), Arc::new(handler))
+}
+
+/// Stop running a handler added with `register`, e.g. because its menu expired. The other
+/// handlers of the message are kept
+pub fn unregister(cx: &Context, message_id: MessageId, registration: Registration) {
+    let mut data = cx.data.write();
+    let menus = data.get_mut::<ReactionMenus>().unwrap();
+    remove_handler(menus, message_id, registration);
+}
+
+/// Stop handling reactions to a message, e.g. because it was deleted
+pub fn remove(cx: &Context, message_id: MessageId) {
+    let mut data = cx.data.write();
+    let menus = data.get_mut::<ReactionMenus>().unwrap();
+    menus.remove(&message_id);
+}
+
+pub fn dispatch(cx: &Context, reaction: &Reaction) -> Result<(), Error> {
+    // Clone the handler out, so that it can register or remove menus itself
+    let handler = find_handler(cx.data.read().get::<ReactionMenus>().unwrap(), reaction);
+    match handler {
+        Some(handler) => handler(cx, reaction),
+        None => Ok(()),
+    }
+}
+
+/// See `register`
+fn add_handler(
+    menus: &mut Menus,
+    message_id: MessageId,
+    emoji: ReactionType,
+    handler: ReactionHandler,
+) -> Registration {
+    static NEXT_REGISTRATION: AtomicU64 = AtomicU64::new(0);
+    let registration = Registration(NEXT_REGISTRATION.fetch_add(1, Ordering::Relaxed));
+    menus
+        .entry(message_id)
+        .or_default()
+        .push((emoji, handler, registration));
+    registration
+}
+
+/// See `unregister`
+fn remove_handler(menus: &mut Menus, message_id: MessageId, registration: Registration) {
+    if let Some(handlers) = menus.get_mut(&message_id) {
+        handlers.retain(|&(_, _, registered)| registered != registration);
+        if handlers.is_empty() {
+            menus.remove(&message_id);
+        }
+    }
+}
+
+/// The handler for the emoji of the reaction on its message, if there is one
+fn find_handler(menus: &Menus, reaction: &Reaction) -> Option<ReactionHandler> {
+    let handlers = menus.get(&reaction.message_id)?;
+    let (_, handler, _) = handlers
+        .iter()
+        .find(|(emoji, _, _)| same_emoji(emoji, &reaction.emoji))?;
+    Some(Arc::clone(handler))
+}
+
+/// Custom emojis are compared by ID only, because the name isn't always sent along
+fn same_emoji(a: &ReactionType, b: &ReactionType) -> bool {
+    match (a, b) {
+        (ReactionType::Custom { id: a, .. }, ReactionType::Custom { id: b, .. }) => a == b,
+        (a, b) => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn reaction(message_id: u64, emoji: serde_json::Value) -> Reaction {
+        serde_json::from_value(json!({
+            "channel_id": "1",
+            "message_id": message_id.to_string(),
+            "user_id": "2",
+            "emoji": emoji,
+        }))
+        .unwrap()
+    }
+
+    fn handler() -> ReactionHandler {
+        Arc::new(|_, _| Ok(()))
+    }
+
+    #[test]
+    fn finds_the_handler_of_the_emoji() {
+        let check = handler();
+        let crab = handler();
+        let custom = ReactionType::Custom {
+            animated: false,
+            id: EmojiId(3),
+            name: Some("ferris".to_owned()),
+        };
+        let mut menus = Menus::new();
+        add_handler(&mut menus, MessageId(10), '✅'.into(), Arc::clone(&check));
+        add_handler(&mut menus, MessageId(10), custom, Arc::clone(&crab));
+
+        let found = |reaction| find_handler(&menus, &reaction);
+        let check_found = found(reaction(10, json!({ "id": null, "name": "✅" }))).unwrap();
+        assert!(Arc::ptr_eq(&check_found, &check));
+        // Custom emojis match by ID, whatever their name
+        let crab_found = found(reaction(10, json!({ "id": "3", "name": null }))).unwrap();
+        assert!(Arc::ptr_eq(&crab_found, &crab));
+
+        assert!(found(reaction(10, json!({ "id": null, "name": "❌" }))).is_none());
+        assert!(found(reaction(11, json!({ "id": null, "name": "✅" }))).is_none());
+    }
+
+    #[test]
+    fn unregisters_only_its_own_handlers() {
+        let old = handler();
+        let new = handler();
+        let mut menus = Menus::new();
+        let old_registration = add_handler(&mut menus, MessageId(10), '▶'.into(), old);
+
+        // The menu is set up again for the same message, before the old one expires
+        menus.remove(&MessageId(10));
+        let new_registration = add_handler(&mut menus, MessageId(10), '▶'.into(), Arc::clone(&new));
+        remove_handler(&mut menus, MessageId(10), old_registration);
+        let found = find_handler(&menus, &reaction(10, json!({ "id": null, "name": "▶" })));
+        assert!(Arc::ptr_eq(&found.unwrap(), &new));
+
+        remove_handler(&mut menus, MessageId(10), new_registration);
+        assert!(menus.is_empty());
+    }
+}